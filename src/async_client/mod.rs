@@ -1,38 +1,268 @@
+// the send errors hand the request back to the caller
+#![allow(clippy::result_large_err)]
+
+mod transport;
 use futures::StreamExt;
+pub use transport::*;
 
 use crate::ClientConfig;
 
 /// A perspective API client, which automatically handles rate limiting and requests.
+///
+/// The client owns the shared response channel and decides when to shut down, requests can be sent through it
+/// directly or through any number of [`ClientHandle`]s.
 pub struct Client {
     thread: tokio::task::JoinHandle<()>,
-    sender: tokio::sync::mpsc::Sender<crate::types::RequestWithPriority>,
+    handle: ClientHandle,
     receiver: Option<tokio::sync::mpsc::Receiver<crate::types::Response>>,
-    killer: Option<tokio::sync::oneshot::Sender<()>>,
+    killer: Option<tokio::sync::oneshot::Sender<Shutdown>>,
+}
+
+/// A cheap, cloneable way to send requests through a [`Client`] from many tasks at once.
+///
+/// Every clone shares the client's queues and rate limit. The background task stops once the client and every
+/// handle have been dropped.
+#[derive(Clone)]
+pub struct ClientHandle {
+    sender: tokio::sync::mpsc::Sender<Event>,
+    rate: crate::rate_limit::SharedRate,
+    next_id: std::sync::Arc<std::sync::atomic::AtomicU64>,
+    /// Room for the requests that can be waiting in the queues, see [`ClientHandle::reserve`].
+    slots: std::sync::Arc<Slots>,
+    cache_stats: crate::cache::SharedStats,
+    journal: Option<std::sync::Arc<crate::journal::JournalFile>>,
+}
+
+/// Everything the client asks of the background thread, in the order it was asked.
+// almost every event is a request, boxing them would only add an allocation
+#[allow(clippy::large_enum_variant)]
+enum Event {
+    Request(Queued),
+    /// Cancel a request if it is still waiting, answering whether it was.
+    Cancel(crate::types::RequestId, tokio::sync::oneshot::Sender<bool>),
+}
+
+/// Room in the queues shared by the client, its handles and the background thread, see [`crate::queue::Capacity`].
+struct Slots {
+    capacity: std::sync::Mutex<crate::queue::Capacity>,
+    freed: tokio::sync::Notify,
+}
+
+/// Room for one request in the queues, given back when dropped.
+struct Slot {
+    slots: std::sync::Arc<Slots>,
+    room: crate::queue::Room,
+}
+
+impl Slots {
+    fn capacity(&self) -> std::sync::MutexGuard<'_, crate::queue::Capacity> {
+        self.capacity.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn try_acquire(self: &std::sync::Arc<Self>, priority: crate::types::Priority, tenant: Option<String>) -> Result<Slot, crate::queue::NoRoom> {
+        let room = self.capacity().take(priority, tenant)?;
+        Ok(Slot { slots: self.clone(), room })
+    }
+
+    /// Wait until there is room at `priority` for `tenant`, fails once the background thread has stopped.
+    async fn acquire(self: &std::sync::Arc<Self>, priority: crate::types::Priority, tenant: Option<String>) -> Result<Slot, crate::queue::NoRoom> {
+        loop {
+            let freed = self.freed.notified();
+            tokio::pin!(freed);
+            // listen before looking, so room freed in between isn't missed
+            freed.as_mut().enable();
+            match self.try_acquire(priority, tenant.clone()) {
+                Err(crate::queue::NoRoom::Full) => freed.await,
+                acquired => return acquired,
+            }
+        }
+    }
+
+    fn close(&self) {
+        self.capacity().close();
+        self.freed.notify_waiters();
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.capacity().give_back(&self.room);
+        // the room may only fit some of the waiters, so every one of them has to look
+        self.slots.freed.notify_waiters();
+    }
+}
+
+/// Sent to the background thread by [`Client::shutdown`].
+struct Shutdown {
+    mode: crate::types::ShutdownMode,
+    unsent: tokio::sync::oneshot::Sender<Vec<crate::types::RequestWithPriority>>,
+}
+
+type Queued = crate::dispatch::Queued<Outbox>;
+
+/// Where the background thread takes room in the queues and delivers responses, see [`crate::dispatch::Outlet`].
+struct Outbox {
+    sender: tokio::sync::mpsc::UnboundedSender<(crate::types::Response, Option<Slot>)>,
+    slots: std::sync::Arc<Slots>,
+}
+
+impl crate::dispatch::Outlet for Outbox {
+    type Slot = Slot;
+    type Responder = tokio::sync::oneshot::Sender<crate::types::Response>;
+
+    fn try_acquire(&self, priority: crate::types::Priority, tenant: Option<String>) -> Option<Slot> {
+        self.slots.try_acquire(priority, tenant).ok()
+    }
+
+    /// Deliver a response to its ticket if it has one, otherwise hand it to [`forward`] for the shared response channel.
+    fn deliver(&self, responder: Option<Self::Responder>, slot: Option<Slot>, res: crate::types::Response) {
+        match responder {
+            Some(responder) => {
+                if responder.send(res).is_err() {
+                    log::info!("response ticket was dropped before the response arrived");
+                }
+            }
+            None => {
+                if self.sender.send((res, slot)).is_err() {
+                    log::error!("failed to send response: the response channel is closed");
+                }
+            }
+        }
+    }
+}
+
+/// Room for one request in the queues, at the priority and for the tenant it was reserved with, see
+/// [`ClientHandle::reserve`].
+///
+/// Dropping the permit gives the room back.
+pub struct Permit<'a> {
+    client: &'a ClientHandle,
+    options: crate::types::RequestOptions,
+    slot: Slot,
+    channel: tokio::sync::mpsc::Permit<'a, Event>,
+}
+
+impl Permit<'_> {
+    /// Like [`ClientHandle::analyze`], without waiting for room. With a [`crate::Journal`] this still waits for the
+    /// request to be written down.
+    pub async fn analyze(self, req: crate::types::Request) -> ResponseTicket {
+        let (responder, receiver) = tokio::sync::oneshot::channel();
+        let id = self.enqueue(req, Some(responder)).await;
+        ResponseTicket { id, receiver }
+    }
+    /// Like [`ClientHandle::send`], without waiting for room. With a [`crate::Journal`] this still waits for the
+    /// request to be written down.
+    pub async fn send(self, req: crate::types::Request) -> crate::types::RequestId {
+        self.enqueue(req, None).await
+    }
+    async fn enqueue(self, req: crate::types::Request, responder: Option<tokio::sync::oneshot::Sender<crate::types::Response>>) -> crate::types::RequestId {
+        let id = crate::types::RequestId(self.client.next_id.fetch_add(1, std::sync::atomic::Ordering::Relaxed));
+        if let Some(journal) = self.client.journal.as_ref() {
+            let unqueued = Unqueued { journal, id };
+            journal.accept(crate::journal::Record::new(id, &req, &self.options)).await;
+            std::mem::forget(unqueued);
+        }
+        self.channel.send(Event::Request(Queued::new(id, req, self.options, responder, self.slot)));
+        id
+    }
+}
+
+/// Answers a request in the journal if the caller gives up on it while it is being written down, so it isn't picked
+/// up again after a restart.
+struct Unqueued<'a> {
+    journal: &'a crate::journal::JournalFile,
+    id: crate::types::RequestId,
+}
+
+impl Drop for Unqueued<'_> {
+    fn drop(&mut self) {
+        self.journal.answer(self.id);
+    }
+}
+
+/// A handle to the response of a single request sent with [`ClientHandle::analyze`].
+///
+/// Resolves to exactly that request's response, regardless of how many other requests are in flight.
+pub struct ResponseTicket {
+    id: crate::types::RequestId,
+    receiver: tokio::sync::oneshot::Receiver<crate::types::Response>,
+}
+
+impl ResponseTicket {
+    /// The id to pass to [`ClientHandle::cancel`].
+    pub fn id(&self) -> crate::types::RequestId {
+        self.id
+    }
+}
+
+impl std::future::Future for ResponseTicket {
+    type Output = crate::types::Response;
+
+    fn poll(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<Self::Output> {
+        std::pin::Pin::new(&mut self.receiver)
+            .poll(cx)
+            .map(|r| r.unwrap_or(Err(crate::types::ApiError::ClientClosed)))
+    }
 }
 
 impl Client {
-    pub async fn new(config: ClientConfig) -> Self {
-        let (req_sender, req_receiver) = tokio::sync::mpsc::channel::<crate::types::RequestWithPriority>(config.request_buffer_size);
+    /// Create a client sending requests with reqwest, configured from [`ClientConfig::http`].
+    ///
+    /// Fails if reqwest can't build an http client from those settings, e.g. because the TLS backend couldn't be
+    /// initialized.
+    pub async fn new(config: ClientConfig) -> Result<Self, crate::types::ApiError> {
+        let transport = ReqwestTransport::new(&config)?;
+        Self::with_transport(config, transport).await
+    }
+    /// Create a client sending requests through your own [`Transport`].
+    ///
+    /// Fails if the threads writing the [`crate::Journal`] or the [`crate::Cache`] to disk can't be spawned.
+    pub async fn with_transport<T: Transport>(config: ClientConfig, transport: T) -> Result<Self, crate::types::ApiError> {
+        let (req_sender, req_receiver) = tokio::sync::mpsc::channel::<Event>(config.request_buffer_size);
         let (res_sender, res_receiver) = tokio::sync::mpsc::channel::<crate::types::Response>(config.response_buffer_size);
-        let (killer_sender, killer_receiver) = tokio::sync::oneshot::channel::<()>();
+        let (killer_sender, killer_receiver) = tokio::sync::oneshot::channel::<Shutdown>();
+
+        let rate = crate::rate_limit::SharedRate::new(config.rate_limit.requests_per_second);
+        let slots = std::sync::Arc::new(Slots {
+            capacity: std::sync::Mutex::new(crate::queue::Capacity::new(&config)),
+            freed: tokio::sync::Notify::new(),
+        });
+        let cache_stats = crate::cache::SharedStats::default();
+        let journal = config.journal.as_ref().map(crate::journal::JournalFile::open).transpose()?.map(std::sync::Arc::new);
+        let (forward_sender, forward_receiver) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(forward(forward_receiver, res_sender));
+        let outbox = Outbox {
+            sender: forward_sender,
+            slots: slots.clone(),
+        };
+        let dispatcher = crate::dispatch::Dispatcher::new(&config, outbox, journal.clone(), cache_stats.clone())?;
+
+        let thread = tokio::spawn(thread(config, transport, req_receiver, dispatcher, killer_receiver, rate.clone(), slots.clone()));
 
-        let thread = tokio::spawn(thread(config, req_receiver, res_sender, killer_receiver));
+        if let Some(journal) = journal.as_ref() {
+            let pending = journal.pending();
+            if !pending.is_empty() {
+                tokio::spawn(resume(req_sender.downgrade(), slots.clone(), pending));
+            }
+        }
 
-        Self {
+        Ok(Self {
             thread,
-            sender: req_sender,
+            handle: ClientHandle {
+                sender: req_sender,
+                rate,
+                next_id: std::sync::Arc::new(std::sync::atomic::AtomicU64::new(journal.as_ref().map_or(0, |j| j.next_id()))),
+                slots,
+                cache_stats,
+                journal,
+            },
             receiver: Some(res_receiver),
             killer: Some(killer_sender),
-        }
-    }
-    pub async fn send_high(&self, req: crate::types::Request) -> Result<(), tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
-        self.sender.send(crate::types::RequestWithPriority::High(req)).await
+        })
     }
-    pub async fn send_normal(&self, req: crate::types::Request) -> Result<(), tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
-        self.sender.send(crate::types::RequestWithPriority::Normal(req)).await
-    }
-    pub async fn send_low(&self, req: crate::types::Request) -> Result<(), tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
-        self.sender.send(crate::types::RequestWithPriority::Low(req)).await
+    /// A handle that can be cloned and moved to other tasks to send requests through this client.
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
     }
     pub async fn recv(&mut self) -> Option<crate::types::Response> {
         match self.receiver.as_mut() {
@@ -44,143 +274,815 @@ impl Client {
     pub fn take_receiver(&mut self) -> Option<tokio::sync::mpsc::Receiver<crate::types::Response>> {
         self.receiver.take()
     }
+    /// Stop the client, returning every request that was accepted but never sent so it can be persisted or rerouted.
+    ///
+    /// Handles that are still around can no longer send requests. Tickets of returned or abandoned requests resolve
+    /// to [`crate::ApiError::ClientClosed`]. The [`crate::Journal`] is up to date once this returns.
+    pub async fn shutdown(mut self, mode: crate::types::ShutdownMode) -> Vec<crate::types::RequestWithPriority> {
+        let Some(killer) = self.killer.take() else {
+            return Vec::new();
+        };
+        let (unsent_sender, unsent_receiver) = tokio::sync::oneshot::channel();
+        if killer.send(Shutdown { mode, unsent: unsent_sender }).is_err() {
+            log::error!("failed to send shutdown signal");
+            return Vec::new();
+        }
+        let unsent = unsent_receiver.await.unwrap_or_default();
+        if let Err(e) = self.thread.await {
+            log::error!("client thread failed: {}", e);
+        }
+        if let Some(journal) = self.handle.journal.as_ref() {
+            journal.flush().await;
+        }
+        unsent
+    }
 }
 
-impl Drop for Client {
-    fn drop(&mut self) {
-        if let Some(killer) = self.killer.take() {
-            if let Err(()) = killer.send(()) {
-                log::error!("failed to send kill signal");
-            }
+impl std::ops::Deref for Client {
+    type Target = ClientHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl ClientHandle {
+    /// The number of requests per second the client is currently sending at.
+    ///
+    /// This is lower than the configured rate while the client is backing off from quota errors.
+    pub fn effective_rate(&self) -> f64 {
+        self.rate.get()
+    }
+    /// How many requests were answered from the [`crate::Cache`] so far, all zero without one.
+    pub fn cache_stats(&self) -> crate::CacheStats {
+        self.cache_stats.get()
+    }
+    /// Queue a request and get a ticket that resolves to its response, waiting for room in the queues if they are full.
+    ///
+    /// The response is not sent to the shared response channel, so this can be mixed freely with `send_*` and `recv`.
+    pub async fn analyze(
+        &self,
+        req: crate::types::Request,
+        options: impl Into<crate::types::RequestOptions>,
+    ) -> Result<ResponseTicket, tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.permit(options.clone(), tenant).await {
+            Ok(permit) => Ok(permit.analyze(req).await),
+            Err(_) => Err(tokio::sync::mpsc::error::SendError(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    /// Queue a request whose response goes to the shared response channel, waiting for room in the queues if they are full.
+    pub async fn send(
+        &self,
+        req: crate::types::Request,
+        options: impl Into<crate::types::RequestOptions>,
+    ) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.permit(options.clone(), tenant).await {
+            Ok(permit) => Ok(permit.send(req).await),
+            Err(_) => Err(tokio::sync::mpsc::error::SendError(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    pub async fn send_high(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::HIGH).await
+    }
+    pub async fn send_normal(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::NORMAL).await
+    }
+    pub async fn send_low(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::LOW).await
+    }
+    /// Like [`ClientHandle::send`], but gives the request back if it has to wait longer than `timeout` for room.
+    pub async fn send_timeout(
+        &self,
+        req: crate::types::Request,
+        options: impl Into<crate::types::RequestOptions>,
+        timeout: std::time::Duration,
+    ) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::SendTimeoutError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match tokio::time::timeout(timeout, self.permit(options.clone(), tenant)).await {
+            Ok(Ok(permit)) => Ok(permit.send(req).await),
+            Ok(Err(_)) => Err(tokio::sync::mpsc::error::SendTimeoutError::Closed(crate::types::RequestWithPriority::new(req, options.priority))),
+            Err(_) => Err(tokio::sync::mpsc::error::SendTimeoutError::Timeout(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    /// Like [`ClientHandle::send`], but gives the request back right away if the queues are full. With a
+    /// [`crate::Journal`] this still waits for the request to be written down.
+    pub async fn try_send(
+        &self,
+        req: crate::types::Request,
+        options: impl Into<crate::types::RequestOptions>,
+    ) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::TrySendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.try_permit(options.clone(), tenant) {
+            Ok(permit) => Ok(permit.send(req).await),
+            Err(tokio::sync::mpsc::error::TrySendError::Full(())) => Err(tokio::sync::mpsc::error::TrySendError::Full(crate::types::RequestWithPriority::new(req, options.priority))),
+            Err(tokio::sync::mpsc::error::TrySendError::Closed(())) => Err(tokio::sync::mpsc::error::TrySendError::Closed(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    pub async fn try_send_high(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::HIGH).await
+    }
+    pub async fn try_send_normal(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::NORMAL).await
+    }
+    pub async fn try_send_low(&self, req: crate::types::Request) -> Result<crate::types::RequestId, tokio::sync::mpsc::error::TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::LOW).await
+    }
+    /// Wait until there is room for a request with these options in the queues and hold on to it.
+    ///
+    /// Room is checked against [`ClientConfig::maximum_queue_size`], [`ClientConfig::level_queue_sizes`] and the
+    /// tenant's [`crate::Fairness::tenant_queue_size`]. Only the tenant in `options` is known here, a request that
+    /// is queued for its `community_id` instead can still be answered with [`crate::ApiError::QueueFull`].
+    pub async fn reserve(&self, options: impl Into<crate::types::RequestOptions>) -> Result<Permit<'_>, tokio::sync::mpsc::error::SendError<()>> {
+        let options = options.into();
+        let tenant = options.tenant.clone();
+        self.permit(options, tenant).await
+    }
+    /// Like [`ClientHandle::reserve`], but fails right away if the queues, the level or the tenant are full.
+    pub fn try_reserve(&self, options: impl Into<crate::types::RequestOptions>) -> Result<Permit<'_>, tokio::sync::mpsc::error::TrySendError<()>> {
+        let options = options.into();
+        let tenant = options.tenant.clone();
+        self.try_permit(options, tenant)
+    }
+    async fn permit(&self, options: crate::types::RequestOptions, tenant: Option<String>) -> Result<Permit<'_>, tokio::sync::mpsc::error::SendError<()>> {
+        let slot = self.slots.acquire(options.priority, tenant).await.map_err(|_| tokio::sync::mpsc::error::SendError(()))?;
+        let channel = self.sender.reserve().await.map_err(|_| tokio::sync::mpsc::error::SendError(()))?;
+        Ok(Permit { client: self, options, slot, channel })
+    }
+    fn try_permit(&self, options: crate::types::RequestOptions, tenant: Option<String>) -> Result<Permit<'_>, tokio::sync::mpsc::error::TrySendError<()>> {
+        let slot = self.slots.try_acquire(options.priority, tenant).map_err(|e| match e {
+            crate::queue::NoRoom::Full => tokio::sync::mpsc::error::TrySendError::Full(()),
+            crate::queue::NoRoom::Closed => tokio::sync::mpsc::error::TrySendError::Closed(()),
+        })?;
+        let channel = self.sender.try_reserve()?;
+        Ok(Permit { client: self, options, slot, channel })
+    }
+    /// Withdraw a request that is still waiting to be sent, its response becomes [`crate::ApiError::Cancelled`].
+    ///
+    /// Returns `false` if the request is already in flight or answered, in which case it can't be cancelled any more.
+    pub async fn cancel(&self, id: crate::types::RequestId) -> bool {
+        let (reply, cancelled) = tokio::sync::oneshot::channel();
+        if self.sender.send(Event::Cancel(id, reply)).await.is_err() {
+            return false;
         }
-        self.thread.abort();
+        cancelled.await.unwrap_or(false)
     }
 }
 
-async fn thread(
+/// Queue the requests a journal had no answer for again, as room frees up, until the client stops.
+///
+/// Their responses go to the shared response channel, the callers that sent them are long gone.
+async fn resume(sender: tokio::sync::mpsc::WeakSender<Event>, slots: std::sync::Arc<Slots>, pending: Vec<crate::journal::Record>) {
+    for record in pending {
+        let options = record.options();
+        let Ok(slot) = slots.acquire(options.priority, options.tenant_of(&record.request)).await else {
+            return;
+        };
+        let Some(sender) = sender.upgrade() else {
+            return;
+        };
+        log::info!("picking up request {} from the journal", record.id);
+        let queued = Queued::new(crate::types::RequestId(record.id), record.request, options, None, slot);
+        if sender.send(Event::Request(queued)).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn thread<T: Transport>(
     config: ClientConfig,
-    mut req_receiver: tokio::sync::mpsc::Receiver<crate::types::RequestWithPriority>,
-    res_sender: tokio::sync::mpsc::Sender<crate::types::Response>,
-    mut killer_receiver: tokio::sync::oneshot::Receiver<()>,
+    transport: T,
+    mut req_receiver: tokio::sync::mpsc::Receiver<Event>,
+    mut dispatcher: crate::dispatch::Dispatcher<Outbox>,
+    mut killer_receiver: tokio::sync::oneshot::Receiver<Shutdown>,
+    rate: crate::rate_limit::SharedRate,
+    slots: std::sync::Arc<Slots>,
 ) {
-    let reqwest_client = reqwest::Client::new();
-    let mut tick = tokio::time::interval(std::time::Duration::from_millis(config.tick_rate));
-    // let mut queue: Vec<crate::types::Request> = Vec::with_capacity(config.maximum_queue_size);
-    let mut low_priority_queue = futures::stream::FuturesOrdered::new();
-    let mut normal_priority_queue = futures::stream::FuturesOrdered::new();
-    let mut high_priority_queue = futures::stream::FuturesOrdered::new();
+    let transport = std::sync::Arc::new(transport);
+    let tape = config.cassette.as_ref().map(|c| std::sync::Arc::new(crate::cassette::Tape::open(c, &config.api_key)));
+    let mut rate_limiter = crate::rate_limit::RateLimiter::new(&config.rate_limit, config.adaptive_rate.clone(), rate, std::time::Instant::now());
+    let mut in_flight = futures::stream::FuturesUnordered::new();
+    // set once a drain has been asked for, along with when to give up on it
+    let mut draining: Option<(tokio::time::Instant, tokio::sync::oneshot::Sender<Vec<crate::types::RequestWithPriority>>)> = None;
+    let mut intake_closed = false;
+    // once the client is dropped only the handles are left, and the thread stops when they are gone too
+    let mut client_dropped = false;
 
     loop {
+        dispatcher.drop_expired();
+        if intake_closed && dispatcher.is_empty() && in_flight.is_empty() {
+            if let Some((_, unsent)) = draining.take() {
+                log::info!("drained all requests");
+                let _ = unsent.send(Vec::new());
+            }
+            break;
+        }
+
+        let next_retry = dispatcher.next_retry().map(tokio::time::Instant::from_std);
+        let drain_deadline = draining.as_ref().map(|(deadline, _)| *deadline);
+        // a request can only go once both its tenant and the client as a whole are below their rate
+        let send_delay = dispatcher
+            .delay(std::time::Instant::now())
+            .filter(|_| in_flight.len() < config.maximum_in_flight)
+            .map(|delay| delay.max(rate_limiter.delay(std::time::Instant::now())));
+        let next_expiry = dispatcher.next_expiry().map(tokio::time::Instant::from_std);
+
         tokio::select! {
-            _ = tick.tick() => {
-                // we only want to run a single request every tick
-                // if let Some(req) = queue.next().await {
-                //     log::info!("sending request");
-                //     if let Err(e) = res_sender.send(req).await {
-                //         log::error!("failed to send response: {}", e);
-                //     }
-                // }
-
-                if let Some(req) = high_priority_queue.next().await {
-                    log::info!("sending high priority request");
-                    if let Err(e) = res_sender.send(req).await {
-                        log::error!("failed to send response: {}", e);
-                    }
-                } else if let Some(req) = normal_priority_queue.next().await {
-                    log::info!("sending normal priority request");
-                    if let Err(e) = res_sender.send(req).await {
-                        log::error!("failed to send response: {}", e);
-                    }
-                } else if let Some(req) = low_priority_queue.next().await {
-                    log::info!("sending low priority request");
-                    if let Err(e) = res_sender.send(req).await {
-                        log::error!("failed to send response: {}", e);
+            _ = tokio::time::sleep(send_delay.unwrap_or_default()), if send_delay.is_some() => {
+                // the response is picked up by the in flight branch, so the api latency never blocks the loop
+                if dispatcher.delay(std::time::Instant::now()) == Some(std::time::Duration::ZERO) && rate_limiter.try_acquire(std::time::Instant::now()) {
+                    if let Some(queued) = dispatcher.pop(std::time::Instant::now()) {
+                        let transport = transport.clone();
+                        let tape = tape.clone();
+                        in_flight.push(async move { (get_response(&queued.request, &*transport, tape.as_deref()).await, queued) });
                     }
                 }
             }
-            Some(req) = req_receiver.recv() => {
-                log::info!("received request");
-
-                match req {
-                    crate::types::RequestWithPriority::Low(req) => {
-                        if low_priority_queue.len() < config.maximum_queue_size {
-                            low_priority_queue.push_back(get_response(
-                                req,
-                                &reqwest_client,
-                                &config.api_key,
-                            ));
-                        } else {
-                            log::info!("low priority queue is full");
-                            if let Err(e) = res_sender.send(Err(crate::types::ApiError::QueueFull)).await {
-                                log::error!("failed to send queue full error: {}", e);
-                            }
-                        }
+            Some((res, queued)) = in_flight.next(), if !in_flight.is_empty() => dispatcher.settle(res, queued, &mut rate_limiter),
+            _ = tokio::time::sleep_until(next_retry.unwrap_or_else(tokio::time::Instant::now)), if next_retry.is_some() => {
+                dispatcher.requeue_due(std::time::Instant::now());
+            }
+            // wakes the loop up so expired requests are answered straight away, rather than whenever something else happens
+            _ = tokio::time::sleep_until(next_expiry.unwrap_or_else(tokio::time::Instant::now)), if next_expiry.is_some() => {}
+            event = req_receiver.recv(), if !intake_closed => match event {
+                Some(Event::Request(queued)) => dispatcher.accept(queued),
+                Some(Event::Cancel(id, reply)) => {
+                    let _ = reply.send(dispatcher.cancel(id));
+                }
+                // a drain has closed the channel and everything buffered in it has been handled
+                None if draining.is_some() => intake_closed = true,
+                None => {
+                    log::info!("client and every handle were dropped, killing thread");
+                    break;
+                }
+            },
+            shutdown = &mut killer_receiver, if draining.is_none() && !client_dropped => {
+                match shutdown {
+                    Ok(Shutdown { mode: crate::types::ShutdownMode::Drain { deadline }, unsent }) => {
+                        log::info!("draining requests");
+                        req_receiver.close();
+                        draining = Some((tokio::time::Instant::now() + deadline, unsent));
                     }
-                    crate::types::RequestWithPriority::Normal(req) => {
-                        if normal_priority_queue.len() < config.maximum_queue_size {
-                            normal_priority_queue.push_back(get_response(
-                                req,
-                                &reqwest_client,
-                                &config.api_key,
-                            ));
-                        } else {
-                            log::info!("normal priority queue is full");
-                            if let Err(e) = res_sender.send(Err(crate::types::ApiError::QueueFull)).await {
-                                log::error!("failed to send queue full error: {}", e);
-                            }
-                        }
+                    Ok(Shutdown { mode: crate::types::ShutdownMode::Immediate, unsent }) => {
+                        log::info!("shutting down");
+                        let _ = unsent.send(take_unsent(&mut req_receiver, &mut dispatcher));
+                        break;
                     }
-                    crate::types::RequestWithPriority::High(req) => {
-                        if high_priority_queue.len() < config.maximum_queue_size {
-                            high_priority_queue.push_back(get_response(
-                                req,
-                                &reqwest_client,
-                                &config.api_key,
-                            ));
-                        } else {
-                            log::info!("high priority queue is full");
-                            if let Err(e) = res_sender.send(Err(crate::types::ApiError::QueueFull)).await {
-                                log::error!("failed to send queue full error: {}", e);
-                            }
-                        }
+                    Err(_) => {
+                        log::info!("client was dropped, serving the remaining handles");
+                        client_dropped = true;
                     }
                 }
-
-                // if queue.len() < config.maximum_queue_size {
-                //     queue.push_back(get_response(
-                //         req,
-                //         &reqwest_client,
-                //         &config.api_key,
-                //     ));
-                // } else {
-                //     log::info!("queue is full");
-                //     if let Err(e) = res_sender.send(Err(crate::types::ApiError::QueueFull)).await {
-                //         log::error!("failed to send queue full error: {}", e);
-                //     }
-                // }
             }
-            _ = &mut killer_receiver => {
-                log::info!("killing thread");
+            _ = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)), if drain_deadline.is_some() => {
+                log::info!("drain deadline passed, shutting down");
+                if let Some((_, unsent)) = draining.take() {
+                    let _ = unsent.send(take_unsent(&mut req_receiver, &mut dispatcher));
+                }
                 break;
             }
         }
     }
+
+    // wakes up anyone waiting for room
+    slots.close();
+}
+
+/// Everything that was accepted but not sent, including what is still in the channel, highest priority first.
+fn take_unsent(req_receiver: &mut tokio::sync::mpsc::Receiver<Event>, dispatcher: &mut crate::dispatch::Dispatcher<Outbox>) -> Vec<crate::types::RequestWithPriority> {
+    req_receiver.close();
+    let arrived = std::iter::from_fn(|| req_receiver.try_recv().ok()).filter_map(|event| match event {
+        Event::Request(queued) => Some(queued),
+        Event::Cancel(..) => None,
+    });
+    dispatcher.take_unsent(arrived)
+}
+
+/// Move responses into the shared response channel as its receiver makes room for them.
+///
+/// Requests sent with `send_*` keep their room in the queues until their response is in the channel, so a consumer
+/// that falls behind makes senders wait for room rather than responses pile up.
+async fn forward(mut responses: tokio::sync::mpsc::UnboundedReceiver<(crate::types::Response, Option<Slot>)>, sender: tokio::sync::mpsc::Sender<crate::types::Response>) {
+    while let Some((res, _slot)) = responses.recv().await {
+        if let Err(e) = sender.send(res).await {
+            log::error!("failed to send response: {}", e);
+        }
+    }
+}
+
+async fn get_response<T: Transport>(req: &crate::types::Request, transport: &T, tape: Option<&crate::cassette::Tape>) -> crate::types::Response {
+    let body = serde_json::to_vec(req).map_err(|e| crate::types::ApiError::Json(e, String::new()))?;
+
+    let res = match tape.filter(|t| t.is_replaying()) {
+        Some(tape) => tape.replay(&body)?,
+        None => transport.send(body.clone()).await?,
+    };
+
+    if let Some(tape) = tape {
+        tape.record(&body, &res);
+    }
+
+    crate::types::RawApiResponse::from_http(res.status, &res.headers, res.body)
 }
 
-async fn get_response(req: crate::types::Request, client: &reqwest::Client, api_key: &str) -> crate::types::Response {
-    // curl -H "Content-Type: application/json" --data \
-    //     '{comment: {text: "what kind of idiot name is foo?"},
-    //        languages: ["en"],
-    //        requestedAttributes: {TOXICITY:{}} }' \
-    // https://commentanalyzer.googleapis.com/v1alpha1/comments:analyze?key=YOUR_KEY_HERE
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, FakeServer};
+    use crate::types::{ApiError, Attribute, AttributeOptions, Priority, Request, RequestBuilder};
+
+    fn request(text: &str) -> Request {
+        RequestBuilder::default()
+            .comment(text)
+            .add_attribute(Attribute::Toxicity, AttributeOptions::default())
+            .build()
+            .unwrap()
+    }
+
+    /// A config that sends one request and then holds the rest back for long enough to see them queued.
+    fn slow_config(server: &FakeServer) -> crate::ClientConfigBuilder {
+        let mut builder = server.config_builder();
+        builder.rate_limit(crate::RateLimit {
+            requests_per_second: 0.1,
+            burst: 1,
+            ..Default::default()
+        });
+        builder
+    }
+
+    /// Wait until the server has received `count` requests.
+    async fn received(server: &FakeServer, count: usize) {
+        while server.request_count() < count {
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+    }
+
+    fn fast_retries() -> crate::RetryPolicy {
+        crate::RetryPolicyBuilder::default()
+            .base_delay(std::time::Duration::from_millis(1))
+            .max_delay(std::time::Duration::from_millis(10))
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_analyze_against_fake_server() {
+        let server = FakeServer::start();
+        server.require_api_key("fake-api-key");
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+
+        let res = client.analyze(request("what kind of idiot name is foo?"), Priority::HIGH).await.unwrap().await.unwrap();
+
+        assert!(res.attribute_scores[&Attribute::Toxicity].summary_score.value > 0.9);
+        assert_eq!(res.attempts, 1);
+        let received = server.requests();
+        assert_eq!(received[0].path, "/v1alpha1/comments:analyze");
+        assert_eq!(received[0].header("x-goog-api-key"), Some("fake-api-key"));
+    }
+
+    #[tokio::test]
+    async fn test_tickets_resolve_to_their_own_response() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(std::time::Duration::from_millis(200)));
+        let mut client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+        let mut shared = request("sent to the channel");
+        shared.client_token = Some("shared".into());
+
+        let toxic = client.analyze(request("what kind of idiot name is foo?"), Priority::NORMAL).await.unwrap();
+        client.send_normal(shared).await.unwrap();
+        let harmless = client.analyze(request("have a nice day"), Priority::NORMAL).await.unwrap();
+
+        let harmless = harmless.await.unwrap();
+        let toxic = toxic.await.unwrap();
+        assert!(toxic.attribute_scores[&Attribute::Toxicity].summary_score.value > 0.9);
+        assert!(harmless.attribute_scores[&Attribute::Toxicity].summary_score.value < 0.5);
+        assert_eq!(client.recv().await.unwrap().unwrap().client_token.as_deref(), Some("shared"));
+    }
+
+    #[tokio::test]
+    async fn test_handles_send_from_many_tasks() {
+        let server = FakeServer::start();
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+
+        let tasks = (0..4)
+            .map(|i| {
+                let handle = client.handle();
+                tokio::spawn(async move { handle.analyze(request(&format!("task {}", i)), Priority::NORMAL).await.unwrap().await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(task.await.unwrap().is_ok());
+        }
+
+        // the client going away leaves the handles working
+        let handle = client.handle();
+        drop(client);
+        assert!(handle.analyze(request("after drop"), Priority::NORMAL).await.unwrap().await.is_ok());
+        assert_eq!(server.request_count(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_thread_stops_when_last_handle_is_dropped() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).await.unwrap();
+        let handle = client.handle();
+        let held = client.analyze(request("held"), Priority::NORMAL).await.unwrap();
+        received(&server, 1).await;
+        let queued = handle.analyze(request("queued"), Priority::NORMAL).await.unwrap();
+
+        drop(client);
+        drop(handle);
+
+        assert!(matches!(held.await, Err(ApiError::ClientClosed)));
+        assert!(matches!(queued.await, Err(ApiError::ClientClosed)));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_does_not_block_others() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(std::time::Duration::from_millis(500)));
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+
+        let slow = client.analyze(request("slow"), Priority::HIGH).await.unwrap();
+        let fast = client.analyze(request("fast"), Priority::HIGH).await.unwrap();
+
+        tokio::time::timeout(std::time::Duration::from_millis(400), fast).await.unwrap().unwrap();
+        slow.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_maximum_in_flight_holds_back_requests() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(std::time::Duration::from_millis(200)));
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).await.unwrap();
+
+        let slow = client.analyze(request("slow"), Priority::HIGH).await.unwrap();
+        let fast = client.analyze(request("fast"), Priority::HIGH).await.unwrap();
+
+        // the fast request only goes once the slow one is answered, so the slow one is answered by now
+        fast.await.unwrap();
+        assert!(futures::FutureExt::now_or_never(slow).unwrap().is_ok());
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_are_coalesced() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(std::time::Duration::from_millis(200)));
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+
+        let mut tickets = Vec::new();
+        for _ in 0..3 {
+            tickets.push(client.analyze(request("lol"), Priority::NORMAL).await.unwrap());
+        }
+        let other = client.analyze(request("other"), Priority::NORMAL).await.unwrap();
+
+        for ticket in tickets {
+            assert!(ticket.await.is_ok());
+        }
+        other.await.unwrap();
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_urgent_request_is_not_held_back_by_an_identical_queued_one() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).await.unwrap();
+        let held = client.analyze(request("held"), Priority::HIGH).await.unwrap();
+        received(&server, 1).await;
+
+        let other = client.analyze(request("other"), Priority::LOW).await.unwrap();
+        let low = client.analyze(request("lol"), Priority::LOW).await.unwrap();
+        let high = client.analyze(request("lol"), Priority::HIGH).await.unwrap();
+        // cancels are handled in order with the requests, so everything above is queued once this returns
+        let barrier = client.analyze(request("barrier"), Priority::LOW).await.unwrap();
+        assert!(client.cancel(barrier.id()).await);
+        server.release();
+
+        held.await.unwrap();
+        high.await.unwrap();
+        low.await.unwrap();
+        other.await.unwrap();
+        let texts = server.requests().iter().map(|r| r.body["comment"]["text"].as_str().unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(texts, ["held", "lol", "other"]);
+    }
+
+    #[tokio::test]
+    async fn test_coalesced_requests_can_match_on_the_error() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold).push_fault(Fault::Status(404));
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).await.unwrap();
+        let held = client.analyze(request("held"), Priority::NORMAL).await.unwrap();
+        received(&server, 1).await;
+
+        let leader = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+        let follower = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+        server.release();
+
+        held.await.unwrap();
+        for res in [leader.await, follower.await] {
+            assert!(matches!(res.unwrap_err().as_inner(), crate::types::ApiError::Status(404, _)));
+        }
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_coalescing_can_be_turned_off() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(std::time::Duration::from_millis(200)));
+        let client = Client::new(server.config_builder().coalesce_requests(false).build().unwrap()).await.unwrap();
+
+        let first = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+        let second = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+
+        first.await.unwrap();
+        second.await.unwrap();
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_request_hands_over_to_identical_one() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+        let leader = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+        let _follower = client.analyze(request("lol"), Priority::NORMAL).await.unwrap();
+
+        assert!(client.cancel(leader.id()).await);
+
+        assert!(matches!(leader.await, Err(ApiError::Cancelled(_))));
+        let unsent = client.shutdown(crate::types::ShutdownMode::Immediate).await;
+        assert_eq!(unsent.len(), 1);
+        assert_eq!(unsent[0].comment.text, "lol");
+    }
+
+    #[tokio::test]
+    async fn test_cached_responses_are_not_sent() {
+        let server = FakeServer::start();
+        let client = Client::new(server.config_builder().cache(crate::Cache::default()).build().unwrap()).await.unwrap();
+
+        let sent = client.analyze(request("lol"), Priority::NORMAL).await.unwrap().await.unwrap();
+        let cached = client.analyze(request("lol"), Priority::NORMAL).await.unwrap().await.unwrap();
+        let options = crate::types::RequestOptions::new(Priority::NORMAL).bypass_cache();
+        let bypassed = client.analyze(request("lol"), options).await.unwrap().await.unwrap();
+
+        assert_eq!((sent.attempts, cached.attempts, bypassed.attempts), (1, 0, 1));
+        assert_eq!(cached.attribute_scores[&Attribute::Toxicity].summary_score.value, sent.attribute_scores[&Attribute::Toxicity].summary_score.value);
+        assert_eq!(server.request_count(), 2);
+        assert_eq!(client.cache_stats(), crate::CacheStats { hits: 1, misses: 1 });
+    }
+
+    #[tokio::test]
+    async fn test_journal_resumes_unanswered_requests() {
+        let server = FakeServer::start();
+        let journal = crate::journal::TestJournal::new("async-resume");
+        let client = Client::new(slow_config(&server).journal(journal.0.clone()).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+        let mut pending = request("pending");
+        pending.client_token = Some("pending".into());
+        client.send(pending, Priority::HIGH).await.unwrap();
+        assert_eq!(client.shutdown(crate::types::ShutdownMode::Immediate).await.len(), 1);
+
+        let mut client = Client::new(server.config_builder().journal(journal.0.clone()).build().unwrap()).await.unwrap();
+        let res = client.recv().await.unwrap().unwrap();
+        assert_eq!(res.client_token.as_deref(), Some("pending"));
+        assert_eq!(server.request_count(), 2);
+
+        client.shutdown(crate::types::ShutdownMode::Drain { deadline: std::time::Duration::from_secs(5) }).await;
+        assert!(crate::journal::JournalFile::open(&journal.0).unwrap().pending().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sent_requests_are_on_disk() {
+        let server = FakeServer::start();
+        let journal = crate::journal::TestJournal::new("async-on-disk");
+        let client = Client::new(slow_config(&server).journal(journal.0.clone()).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+
+        client.try_send_low(request("queued")).await.unwrap();
+        let written = std::fs::read_to_string(&journal.0.path).unwrap();
+        assert!(written.lines().any(|line| line.contains("\"queued\"")));
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::ServerError(503));
+        let client = Client::new(server.config_builder().retry_policy(fast_retries()).build().unwrap()).await.unwrap();
+
+        let res = client.analyze(request("hello"), Priority::NORMAL).await.unwrap().await.unwrap();
+
+        assert_eq!(res.attempts, 2);
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_drain_finishes_queued_requests() {
+        let server = FakeServer::start();
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
+
+        let tickets = vec![
+            client.analyze(request("one"), Priority::NORMAL).await.unwrap(),
+            client.analyze(request("two"), Priority::LOW).await.unwrap(),
+        ];
+        let unsent = client.shutdown(crate::types::ShutdownMode::Drain { deadline: std::time::Duration::from_secs(5) }).await;
+
+        assert!(unsent.is_empty());
+        for ticket in tickets {
+            ticket.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_immediate_shutdown_returns_unsent_requests() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).await.unwrap();
+        let held = client.analyze(request("held"), Priority::NORMAL).await.unwrap();
+        received(&server, 1).await;
+
+        let low = client.analyze(request("low"), Priority::LOW).await.unwrap();
+        let high = client.analyze(request("high"), Priority::HIGH).await.unwrap();
+        let unsent = client.shutdown(crate::types::ShutdownMode::Immediate).await;
+
+        // requests already sent aren't handed back
+        assert_eq!(unsent.iter().map(|r| r.priority()).collect::<Vec<_>>(), vec![Priority::HIGH, Priority::LOW]);
+        assert!(matches!(held.await, Err(ApiError::ClientClosed)));
+        assert!(matches!(low.await, Err(ApiError::ClientClosed)));
+        assert!(matches!(high.await, Err(ApiError::ClientClosed)));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_cancel_queued_request() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).build().unwrap()).await.unwrap();
+        let sent = client.analyze(request("sent"), Priority::NORMAL).await.unwrap();
+        let sent_id = sent.id();
+        sent.await.unwrap();
+
+        let ticket = client.analyze(request("deleted comment"), Priority::NORMAL).await.unwrap();
+        let id = ticket.id();
+
+        assert!(client.cancel(id).await);
+        assert!(matches!(ticket.await, Err(ApiError::Cancelled(cancelled)) if cancelled == id));
+        assert!(!client.cancel(id).await);
+        assert!(!client.cancel(sent_id).await);
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_request_is_not_sent() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+
+        let options = crate::types::RequestOptions::new(Priority::LOW).ttl(std::time::Duration::from_millis(50));
+        let ticket = client.analyze(request("stale"), options).await.unwrap();
+        let id = ticket.id();
+
+        let res = tokio::time::timeout(std::time::Duration::from_secs(1), ticket).await.unwrap();
+        assert!(matches!(res, Err(ApiError::Expired(expired)) if expired == id));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_when_queue_is_full() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).maximum_queue_size(1).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+
+        let queued = client.try_send_low(request("queued")).await.unwrap();
+        let full = client.try_send_high(request("full")).await;
+        let timed_out = client.send_timeout(request("timed out"), Priority::HIGH, std::time::Duration::from_millis(50)).await;
+
+        assert!(matches!(full, Err(tokio::sync::mpsc::error::TrySendError::Full(r)) if r.priority() == Priority::HIGH));
+        assert!(matches!(timed_out, Err(tokio::sync::mpsc::error::SendTimeoutError::Timeout(_))));
+        assert!(client.try_reserve(Priority::LOW).is_err());
+        assert!(client.cancel(queued).await);
+        client.try_reserve(Priority::LOW).unwrap().send(request("room again")).await;
+    }
+
+    #[tokio::test]
+    async fn test_unread_responses_hold_room_without_blocking_tickets() {
+        let server = FakeServer::start();
+        let mut client = Client::new(server.config_builder().response_buffer_size(1).maximum_queue_size(2).build().unwrap()).await.unwrap();
+
+        client.send_normal(request("one")).await.unwrap();
+        client.send_normal(request("two")).await.unwrap();
+        let ticket = client.analyze(request("ticket"), Priority::NORMAL).await.unwrap();
+        tokio::time::timeout(std::time::Duration::from_secs(5), ticket).await.unwrap().unwrap();
+        client.send_normal(request("three")).await.unwrap();
+
+        // "two" and "three" keep their room until "one" is read and makes space in the channel
+        assert!(tokio::time::timeout(std::time::Duration::from_millis(100), client.reserve(Priority::NORMAL)).await.is_err());
+        for _ in 0..3 {
+            client.recv().await.unwrap().unwrap();
+        }
+        tokio::time::timeout(std::time::Duration::from_secs(5), client.reserve(Priority::NORMAL)).await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_full_level_or_tenant_is_reported_when_sending() {
+        let server = FakeServer::start();
+        let fairness = crate::FairnessBuilder::default().tenant_queue_size(1).build().unwrap();
+        let client = Client::new(slow_config(&server).level_queue_size(Priority::LOW, 1).fairness(fairness).build().unwrap()).await.unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).await.unwrap().await.unwrap();
+        let noisy = crate::types::RequestOptions::new(Priority::HIGH).tenant("noisy");
+
+        client.try_send_low(request("low")).await.unwrap();
+        client.try_send(request("noisy"), noisy.clone()).await.unwrap();
 
-    let url = format!("https://commentanalyzer.googleapis.com/v1alpha1/comments:analyze?key={}", api_key);
+        assert!(matches!(client.try_send_low(request("full level")).await, Err(tokio::sync::mpsc::error::TrySendError::Full(_))));
+        assert!(matches!(client.try_send(request("full tenant"), noisy.clone()).await, Err(tokio::sync::mpsc::error::TrySendError::Full(_))));
+        assert!(client.try_reserve(noisy).is_err());
+        client.try_send_high(request("other level and tenant")).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_quota_exceeded_lowers_rate() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::QuotaExceeded { retry_after: None });
+        let client = Client::new(server.config_builder().retry_policy(crate::RetryPolicy::never()).build().unwrap()).await.unwrap();
 
-    let res = client.post(&url).json(&req).send().await?;
+        let res = client.analyze(request("hello"), Priority::LOW).await.unwrap().await;
+
+        assert!(matches!(res, Err(ApiError::QuotaExceeded { .. })));
+        assert_eq!(client.effective_rate(), 500.0);
+    }
 
-    let body = res.text().await?;
+    #[tokio::test]
+    async fn test_error_responses() {
+        let server = FakeServer::start();
+        server.require_api_key("another key");
+        server.push_fault(Fault::Malformed).push_fault(Fault::EmptyResponse);
+        let client = Client::new(server.config_builder().build().unwrap()).await.unwrap();
 
-    let res = serde_json::from_str::<crate::types::RawApiResponse>(&body).map_err(|e| crate::types::ApiError::Json(e, body))?;
+        let malformed = client.analyze(request("hello"), Priority::NORMAL).await.unwrap().await;
+        let empty = client.analyze(request("hello"), Priority::NORMAL).await.unwrap().await;
+        let invalid_key = client.analyze(request("hello"), Priority::NORMAL).await.unwrap().await;
 
-    res.extract()
+        assert!(matches!(malformed, Err(ApiError::Json(..))));
+        assert!(matches!(empty, Err(ApiError::EmptyResponse(_))));
+        assert!(matches!(invalid_key, Err(ApiError::InvalidApiKey)));
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay_cassette() {
+        let path = std::env::temp_dir().join(format!("perspective-rs-cassette-{}.jsonl", std::process::id()));
+
+        {
+            let server = FakeServer::start();
+            let config = server.config_builder().cassette(crate::Cassette::Record(path.clone())).build().unwrap();
+            let client = Client::new(config).await.unwrap();
+            client.analyze(request("you idiot"), Priority::HIGH).await.unwrap().await.unwrap();
+        }
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        assert!(!recorded.contains("fake-api-key"));
+
+        let config = crate::ClientConfigBuilder::default()
+            .api_key("unused".into())
+            .cassette(crate::Cassette::Replay(path.clone()))
+            .build()
+            .unwrap();
+        let client = Client::new(config).await.unwrap();
+        let replayed = client.analyze(request("you idiot"), Priority::HIGH).await.unwrap().await.unwrap();
+        let unmatched = client.analyze(request("never recorded"), Priority::HIGH).await.unwrap().await;
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(replayed.attribute_scores[&Attribute::Toxicity].summary_score.value > 0.9);
+        assert!(matches!(unmatched, Err(ApiError::Cassette(_))));
+    }
+
+    struct InMemoryTransport;
+
+    impl Transport for InMemoryTransport {
+        async fn send(&self, body: Vec<u8>) -> Result<crate::TransportResponse, ApiError> {
+            let request = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+            assert_eq!(request["comment"]["text"], "in memory");
+            Ok(crate::TransportResponse {
+                status: reqwest::StatusCode::OK,
+                headers: reqwest::header::HeaderMap::new(),
+                body: r#"{"attributeScores": {"TOXICITY": {"summaryScore": {"value": 0.5, "type": "PROBABILITY"}}}, "languages": ["en"]}"#.into(),
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_transport() {
+        let config = crate::ClientConfigBuilder::default().api_key("unused".into()).build().unwrap();
+        let client = Client::with_transport(config, InMemoryTransport).await.unwrap();
+
+        let res = client.analyze(request("in memory"), Priority::NORMAL).await.unwrap().await.unwrap();
+
+        assert_eq!(res.attribute_scores[&Attribute::Toxicity].summary_score.value, 0.5);
+    }
 }
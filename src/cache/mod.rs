@@ -0,0 +1,532 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use sha2::Digest;
+
+use crate::types::{ApiResponse, Request};
+
+/// Keep successful responses and answer identical requests from them, without sending them or using any of the rate.
+///
+/// Requests are identical when their comment text, requested attributes and their options, languages and span
+/// annotations are. Once `capacity` responses are cached the least recently used one makes room for the next, and
+/// responses older than `ttl` are never used. See [`crate::RequestOptions::bypass_cache`] to skip it for one request.
+#[derive(derive_builder::Builder, Clone, Debug, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct Cache {
+    /// The maximum number of responses kept.
+    #[builder(default = "1024")]
+    pub capacity: usize,
+    /// How long a response is used for after it was received, also across restarts when the cache is kept in `path`.
+    #[builder(default = "Duration::from_secs(60 * 60)")]
+    pub ttl: Duration,
+    /// Also keep the responses in this JSON lines file, which is loaded again when a client is created with it.
+    ///
+    /// Each line has the request's fingerprint, when the response was received and the response itself. The file
+    /// should only be used by one client at a time.
+    #[builder(default, setter(into, strip_option))]
+    pub path: Option<PathBuf>,
+    /// Never write comment text to `path`, only the fingerprint of the request it belongs to.
+    #[builder(default)]
+    pub hash_only: bool,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            ttl: Duration::from_secs(60 * 60),
+            path: None,
+            hash_only: false,
+        }
+    }
+}
+
+impl Cache {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.capacity == 0 {
+            return Err("cache capacity cannot be 0".into());
+        }
+
+        if self.ttl.is_zero() {
+            return Err("cache ttl cannot be 0".into());
+        }
+
+        if let Some(path) = self.path.as_ref() {
+            if path.is_dir() {
+                return Err(format!("cache path {} is a directory", path.display()));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl CacheBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let defaults = Cache::default();
+        Cache {
+            capacity: self.capacity.unwrap_or(defaults.capacity),
+            ttl: self.ttl.unwrap_or(defaults.ttl),
+            path: self.path.clone().flatten(),
+            ..defaults
+        }
+        .validate()
+    }
+}
+
+/// How often requests were answered from the cache, see [`crate::ClientHandle::cache_stats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Requests that were looked up but had to be sent, bypassed requests aren't counted.
+    pub misses: u64,
+}
+
+/// The cache counters, shared between the background thread and the client.
+#[derive(Clone, Default)]
+pub(crate) struct SharedStats(std::sync::Arc<(AtomicU64, AtomicU64)>);
+
+impl SharedStats {
+    pub(crate) fn get(&self) -> CacheStats {
+        CacheStats {
+            hits: self.0 .0.load(Ordering::Relaxed),
+            misses: self.0 .1.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What a request is cached by, `None` if it can't be serialized.
+///
+/// The fingerprint is a SHA-256 of the parts of the request that affect the scores, normalized so that the order of
+/// attributes and languages doesn't matter. It stays the same across restarts. The client token and the rest are left
+/// out, unlike in the key requests are coalesced by, since a cached response is handed out with the asking request's
+/// own client token.
+fn key(req: &Request) -> Option<String> {
+    let mut languages = req.languages.iter().flatten().map(serde_json::to_string).collect::<Result<Vec<_>, _>>().ok()?;
+    languages.sort();
+    languages.dedup();
+    // going through a value sorts the attributes
+    let normalized = serde_json::json!({
+        "text": req.comment.text,
+        "attributes": serde_json::to_value(&req.requested_attributes).ok()?,
+        "languages": languages,
+        "spans": req.span_annotations,
+    });
+    Some(sha2::Sha256::digest(normalized.to_string()).iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// A cached response along with what it is stored with.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+struct Entry {
+    fingerprint: String,
+    /// `None` with [`Cache::hash_only`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    text: Option<String>,
+    /// When the response was received, in milliseconds since the unix epoch.
+    stored: u64,
+    response: ApiResponse,
+}
+
+impl Entry {
+    fn is_expired(&self, ttl: Duration, now: SystemTime) -> bool {
+        millis(now).saturating_sub(self.stored) >= ttl.as_millis() as u64
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// Where a [`Cache`] keeps its responses.
+trait Store: Send {
+    /// The response stored for `fingerprint`, unless it has expired.
+    fn get(&mut self, fingerprint: &str, now: SystemTime) -> Option<ApiResponse>;
+    fn put(&mut self, entry: Entry);
+    /// Wait until everything stored so far has been written.
+    #[cfg(test)]
+    fn flush(&self) {}
+}
+
+/// A cache owned by the background thread.
+pub(crate) struct ResponseCache {
+    store: Box<dyn Store>,
+    hash_only: bool,
+    stats: SharedStats,
+}
+
+impl ResponseCache {
+    /// Load the cache, which fails only if the thread writing it to disk can't be spawned.
+    pub(crate) fn open(config: &Cache, stats: SharedStats) -> std::io::Result<Self> {
+        let store: Box<dyn Store> = match config.path.as_ref() {
+            Some(path) => Box::new(DiskStore::open(path, config)?),
+            None => Box::new(Lru::new(config)),
+        };
+        Ok(Self {
+            store,
+            hash_only: config.hash_only,
+            stats,
+        })
+    }
+
+    /// The cached response to `req`, with the request's own client token and `attempts` set to 0.
+    pub(crate) fn get(&mut self, req: &Request, now: SystemTime) -> Option<ApiResponse> {
+        let response = key(req).and_then(|key| self.store.get(&key, now));
+        let counter = match response {
+            Some(_) => &self.stats.0 .0,
+            None => &self.stats.0 .1,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        response.map(|mut response| {
+            response.client_token = req.client_token.clone();
+            response.attempts = 0;
+            response
+        })
+    }
+
+    pub(crate) fn put(&mut self, req: &Request, response: &ApiResponse, now: SystemTime) {
+        let Some(fingerprint) = key(req) else {
+            return;
+        };
+        self.store.put(Entry {
+            fingerprint,
+            text: (!self.hash_only).then(|| req.comment.text.clone()),
+            stored: millis(now),
+            response: response.clone(),
+        });
+    }
+
+    #[cfg(test)]
+    fn flush(&self) {
+        self.store.flush();
+    }
+}
+
+/// Responses kept in memory, the least recently used one is evicted first.
+struct Lru {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, (Entry, u64)>,
+    /// Every fingerprint by when it was last used, the first one is evicted next.
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+}
+
+impl Lru {
+    fn new(config: &Cache) -> Self {
+        Self {
+            capacity: config.capacity,
+            ttl: config.ttl,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            clock: 0,
+        }
+    }
+
+    fn remove(&mut self, fingerprint: &str) {
+        if let Some((_, used)) = self.entries.remove(fingerprint) {
+            self.recency.remove(&used);
+        }
+    }
+
+    /// Every entry, least recently used first.
+    fn iter(&self) -> impl Iterator<Item = &Entry> {
+        self.recency.values().filter_map(|fingerprint| self.entries.get(fingerprint)).map(|(entry, _)| entry)
+    }
+}
+
+impl Store for Lru {
+    fn get(&mut self, fingerprint: &str, now: SystemTime) -> Option<ApiResponse> {
+        let (entry, used) = self.entries.get_mut(fingerprint)?;
+        if entry.is_expired(self.ttl, now) {
+            self.remove(fingerprint);
+            return None;
+        }
+
+        self.recency.remove(used);
+        self.clock += 1;
+        *used = self.clock;
+        let response = entry.response.clone();
+        self.recency.insert(self.clock, fingerprint.to_string());
+        Some(response)
+    }
+
+    fn put(&mut self, entry: Entry) {
+        self.remove(&entry.fingerprint);
+        self.clock += 1;
+        self.recency.insert(self.clock, entry.fingerprint.clone());
+        self.entries.insert(entry.fingerprint.clone(), (entry, self.clock));
+
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else {
+                break;
+            };
+            self.entries.remove(&oldest);
+        }
+    }
+}
+
+/// An [`Lru`] that is also written to a file, so it survives restarts.
+///
+/// New responses are appended to the file, which is rewritten with only the live entries when it is opened and
+/// whenever it has grown to twice the capacity. The file is written by a thread of its own, so the background thread
+/// never waits on the disk. Writing is best effort, the cache keeps working in memory if it fails.
+struct DiskStore {
+    memory: Lru,
+    writes: std::sync::mpsc::Sender<Job>,
+    /// The number of lines in the file, once the writer has caught up.
+    lines: usize,
+}
+
+/// What the writer thread of a [`DiskStore`] is asked to do.
+enum Job {
+    Append(Entry),
+    /// Rewrite the file with only these entries.
+    Compact(Vec<Entry>),
+    /// Answered once everything sent before it has been written.
+    #[cfg(test)]
+    Flush(std::sync::mpsc::SyncSender<()>),
+}
+
+/// The file of a [`DiskStore`], owned by its writer thread.
+struct CacheFile {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+}
+
+impl DiskStore {
+    fn open(path: &Path, config: &Cache) -> std::io::Result<Self> {
+        let mut memory = Lru::new(config);
+        let now = SystemTime::now();
+        match std::fs::File::open(path) {
+            Ok(file) => {
+                // later lines replace earlier ones, and a line cut short by a crash is skipped
+                for line in std::io::BufReader::new(file).lines().map_while(Result::ok) {
+                    if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                        if !entry.is_expired(config.ttl, now) {
+                            memory.put(entry);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("failed to load cache {}: {}", path.display(), e),
+        }
+        log::info!("loaded {} cached responses from {}", memory.entries.len(), path.display());
+
+        let file = CacheFile {
+            path: path.to_path_buf(),
+            file: None,
+        };
+        let (writes, receiver) = std::sync::mpsc::channel();
+        // the writer stops once the store is dropped, after finishing what it was given
+        std::thread::Builder::new().name("perspective-rs-cache-writer".into()).spawn(move || file.run(receiver))?;
+        let mut store = Self { memory, writes, lines: 0 };
+        store.compact();
+        Ok(store)
+    }
+
+    /// Have the file rewritten with only what is in memory.
+    fn compact(&mut self) {
+        self.lines = self.memory.entries.len();
+        self.send(Job::Compact(self.memory.iter().cloned().collect()));
+    }
+
+    fn send(&self, job: Job) {
+        if self.writes.send(job).is_err() {
+            log::error!("failed to write cache: the writer thread has stopped");
+        }
+    }
+}
+
+impl Store for DiskStore {
+    fn get(&mut self, fingerprint: &str, now: SystemTime) -> Option<ApiResponse> {
+        self.memory.get(fingerprint, now)
+    }
+
+    fn put(&mut self, entry: Entry) {
+        self.send(Job::Append(entry.clone()));
+        self.lines += 1;
+        self.memory.put(entry);
+
+        if self.lines >= 2 * self.memory.capacity {
+            self.compact();
+        }
+    }
+
+    #[cfg(test)]
+    fn flush(&self) {
+        let (done, flushed) = std::sync::mpsc::sync_channel(1);
+        self.send(Job::Flush(done));
+        let _ = flushed.recv();
+    }
+}
+
+impl CacheFile {
+    /// Carry out writes until the [`DiskStore`] is dropped.
+    fn run(mut self, jobs: std::sync::mpsc::Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::Append(entry) => self.append(&entry),
+                Job::Compact(entries) => self.compact(&entries),
+                #[cfg(test)]
+                Job::Flush(done) => {
+                    let _ = done.send(());
+                }
+            }
+        }
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_vec(entry).map_err(std::io::Error::from).and_then(|mut line| {
+            line.push(b'\n');
+            file.write_all(&line)
+        });
+        if let Err(e) = written {
+            log::error!("failed to write cache {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn compact(&mut self, entries: &[Entry]) {
+        self.file = None;
+        // e.g. state.cache.tmp, so a journal at state.journal doesn't rewrite through the same file
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let written = std::fs::File::create(&temporary)
+            .and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                for entry in entries {
+                    serde_json::to_writer(&mut writer, entry)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()
+            })
+            .and_then(|()| std::fs::rename(&temporary, &self.path))
+            .and_then(|()| std::fs::OpenOptions::new().append(true).open(&self.path));
+        match written {
+            Ok(file) => self.file = Some(file),
+            Err(e) => log::error!("failed to write cache {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Attribute, AttributeOptions, LanguageCode, RequestBuilder};
+
+    fn request(text: &str) -> Request {
+        RequestBuilder::default().comment(text).add_attribute(Attribute::Toxicity, AttributeOptions::default()).build().unwrap()
+    }
+
+    fn response() -> ApiResponse {
+        ApiResponse {
+            attribute_scores: HashMap::new(),
+            languages: Vec::new(),
+            client_token: Some("first".into()),
+            attempts: 2,
+        }
+    }
+
+    fn cache(config: &Cache) -> (ResponseCache, SharedStats) {
+        let stats = SharedStats::default();
+        (ResponseCache::open(config, stats.clone()).unwrap(), stats)
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let (mut cache, stats) = cache(&CacheBuilder::default().capacity(2).build().unwrap());
+        let now = SystemTime::now();
+        cache.put(&request("a"), &response(), now);
+        cache.put(&request("b"), &response(), now);
+
+        assert!(cache.get(&request("a"), now).is_some());
+        cache.put(&request("c"), &response(), now);
+
+        assert!(cache.get(&request("a"), now).is_some());
+        assert!(cache.get(&request("b"), now).is_none());
+        assert!(cache.get(&request("c"), now).is_some());
+        assert_eq!(stats.get(), CacheStats { hits: 3, misses: 1 });
+    }
+
+    #[test]
+    fn test_expires_after_ttl() {
+        let (mut cache, _) = cache(&CacheBuilder::default().ttl(Duration::from_secs(10)).build().unwrap());
+        let now = SystemTime::now();
+        cache.put(&request("a"), &response(), now);
+
+        let cached = cache.get(&request("a"), now + Duration::from_secs(9)).unwrap();
+        assert_eq!(cached.attempts, 0);
+        assert_eq!(cached.client_token, None);
+        assert!(cache.get(&request("a"), now + Duration::from_secs(10)).is_none());
+    }
+
+    #[test]
+    fn test_key_ignores_order_and_unrelated_fields() {
+        let mut languages = RequestBuilder::default();
+        languages
+            .comment("a")
+            .add_attribute(Attribute::Toxicity, AttributeOptions::default())
+            .add_attribute(Attribute::Insult, AttributeOptions::default())
+            .languages(vec![LanguageCode::English, LanguageCode::German]);
+        let mut reordered = languages.clone();
+        reordered.languages(vec![LanguageCode::German, LanguageCode::English]).client_token(Some("token".into()));
+
+        assert_eq!(key(&languages.build().unwrap()), key(&reordered.build().unwrap()));
+        assert_ne!(key(&request("a")), key(&request("b")));
+        assert_eq!(key(&request("a")).unwrap().len(), 64);
+    }
+
+    #[test]
+    fn test_disk_cache_survives_restarts() {
+        let path = std::env::temp_dir().join(format!("perspective-rs-cache-{}.jsonl", std::process::id()));
+        let config = CacheBuilder::default().capacity(2).path(&path).build().unwrap();
+        let now = SystemTime::now();
+        {
+            let (mut cache, _) = cache(&config);
+            for text in ["a", "b", "c", "d", "e"] {
+                cache.put(&request(text), &response(), now);
+            }
+            cache.flush();
+        }
+
+        let (mut cache, _) = cache(&config);
+        cache.flush();
+        assert!(cache.get(&request("c"), now).is_none());
+        assert!(cache.get(&request("d"), now).is_some());
+        assert!(cache.get(&request("e"), now).is_some());
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(written.lines().count(), 2);
+        assert!(written.contains("\"text\":\"e\""));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_cache_hash_only() {
+        let path = std::env::temp_dir().join(format!("perspective-rs-cache-hash-only-{}.jsonl", std::process::id()));
+        let config = CacheBuilder::default().path(&path).hash_only(true).build().unwrap();
+        let (mut cache, _) = cache(&config);
+
+        cache.put(&request("something private"), &response(), SystemTime::now());
+        cache.flush();
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(!written.contains("something private"));
+        assert!(written.contains(&key(&request("something private")).unwrap()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_cache_builder_validate() {
+        assert!(CacheBuilder::default().capacity(0).build().is_err());
+        assert!(CacheBuilder::default().ttl(Duration::ZERO).build().is_err());
+        assert!(CacheBuilder::default().path(std::env::temp_dir()).build().is_err());
+        assert_eq!(CacheBuilder::default().build().unwrap(), Cache::default());
+    }
+}
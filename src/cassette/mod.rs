@@ -0,0 +1,175 @@
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::transport::TransportResponse;
+
+/// Record the API's answers to a file, or answer from such a file instead of calling the API.
+///
+/// A cassette is a JSON lines file, one request and its raw response per line. Replaying matches on the serialized
+/// [`crate::Request`], identical requests get their recorded responses in the order they were recorded. A request
+/// that was never recorded fails with [`crate::ApiError::Cassette`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Cassette {
+    /// Send requests to the API as usual and write every response to this file, replacing what was in it.
+    Record(PathBuf),
+    /// Never call the API, answer from this file.
+    Replay(PathBuf),
+}
+
+impl Cassette {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            Cassette::Record(_) => Ok(()),
+            Cassette::Replay(path) if path.is_file() => Ok(()),
+            Cassette::Replay(path) => Err(format!("cassette {} does not exist", path.display())),
+        }
+    }
+}
+
+/// A recorded request and the raw response to it.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub(crate) struct Entry {
+    request: serde_json::Value,
+    status: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retry_after: Option<String>,
+    body: String,
+}
+
+/// An open cassette.
+#[derive(Debug)]
+pub(crate) enum Tape {
+    /// `None` if the file couldn't be created.
+    Recording { file: Mutex<Option<std::fs::File>>, api_key: crate::ApiKey },
+    /// Loading errors are kept so every request can report them.
+    Playing(Result<Mutex<Vec<(Entry, bool)>>, String>),
+}
+
+impl Tape {
+    pub(crate) fn open(cassette: &Cassette, api_key: &crate::ApiKey) -> Self {
+        match cassette {
+            Cassette::Record(path) => {
+                // recording is best effort, the requests themselves still go through
+                let file = std::fs::File::create(path)
+                    .map_err(|e| log::error!("failed to create cassette {}: {}", path.display(), e))
+                    .ok();
+                Tape::Recording {
+                    file: Mutex::new(file),
+                    api_key: api_key.clone(),
+                }
+            }
+            Cassette::Replay(path) => Tape::Playing(load(path).map(Mutex::new).map_err(|e| format!("failed to load cassette {}: {}", path.display(), e))),
+        }
+    }
+
+    pub(crate) fn is_replaying(&self) -> bool {
+        matches!(self, Tape::Playing(_))
+    }
+
+    /// Answer a serialized request from the cassette.
+    pub(crate) fn replay(&self, req: &[u8]) -> Result<TransportResponse, crate::types::ApiError> {
+        let Tape::Playing(entries) = self else {
+            return Err(crate::types::ApiError::Cassette("cassette is not in replay mode".into()));
+        };
+        let entries = entries.as_ref().map_err(|e| crate::types::ApiError::Cassette(e.clone()))?;
+        let request = serde_json::from_slice::<serde_json::Value>(req).map_err(|e| crate::types::ApiError::Cassette(e.to_string()))?;
+
+        let mut entries = entries.lock().unwrap_or_else(|e| e.into_inner());
+        let position = entries
+            .iter()
+            .position(|(entry, used)| !used && entry.request == request)
+            .or_else(|| entries.iter().rposition(|(entry, _)| entry.request == request));
+        let Some(position) = position else {
+            log::error!("no recorded response for request {}", request);
+            return Err(crate::types::ApiError::Cassette(format!("no recorded response for request {}", request)));
+        };
+        entries[position].1 = true;
+        let entry = &entries[position].0;
+
+        let status = reqwest::StatusCode::from_u16(entry.status).map_err(|e| crate::types::ApiError::Cassette(e.to_string()))?;
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(value) = entry.retry_after.as_ref().and_then(|v| v.parse().ok()) {
+            headers.insert(reqwest::header::RETRY_AFTER, value);
+        }
+        Ok(TransportResponse {
+            status,
+            headers,
+            body: entry.body.clone(),
+        })
+    }
+
+    /// Write a serialized request and the API's response to it to the cassette, if recording.
+    pub(crate) fn record(&self, req: &[u8], res: &TransportResponse) {
+        let Tape::Recording { file, api_key } = self else {
+            return;
+        };
+
+        let mut request = match serde_json::from_slice::<serde_json::Value>(req) {
+            Ok(request) => request,
+            Err(e) => {
+                log::error!("failed to parse request for cassette: {}", e);
+                return;
+            }
+        };
+        let mut body = res.body.clone();
+        // the key is never sent in the body, but an error message could echo it back
+        let api_key = api_key.expose();
+        if !api_key.is_empty() {
+            body = body.replace(api_key, "<redacted>");
+            scrub_value(&mut request, api_key);
+        }
+
+        let entry = Entry {
+            request,
+            status: res.status.as_u16(),
+            retry_after: res.headers.get(reqwest::header::RETRY_AFTER).and_then(|v| v.to_str().ok()).map(str::to_string),
+            body,
+        };
+
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        let Some(file) = file.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_string(&entry).map_err(std::io::Error::from).and_then(|line| writeln!(file, "{}", line));
+        if let Err(e) = written {
+            log::error!("failed to write to cassette: {}", e);
+        }
+    }
+}
+
+fn load(path: &std::path::Path) -> std::io::Result<Vec<(Entry, bool)>> {
+    let file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut entries = Vec::new();
+    for line in file.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        entries.push((serde_json::from_str::<Entry>(&line)?, false));
+    }
+    Ok(entries)
+}
+
+fn scrub_value(value: &mut serde_json::Value, secret: &str) {
+    match value {
+        serde_json::Value::String(s) if s.contains(secret) => *s = s.replace(secret, "<redacted>"),
+        serde_json::Value::Array(values) => values.iter_mut().for_each(|v| scrub_value(v, secret)),
+        serde_json::Value::Object(map) => map.values_mut().for_each(|v| scrub_value(v, secret)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_tape_debug_redacts_api_key() {
+        let path = std::env::temp_dir().join(format!("perspective-rs-tape-debug-{}.jsonl", std::process::id()));
+        let tape = Tape::open(&Cassette::Record(path.clone()), &crate::ApiKey::new("secret-key"));
+
+        assert!(!format!("{:?}", tape).contains("secret-key"));
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+
+use crate::types::ApiError;
+
+/// The key identical requests are coalesced by, `None` if the request can't be serialized.
+///
+/// Goes through a [`serde_json::Value`] so that the requested attributes always come out in the same order. Unlike the
+/// [`crate::Cache`]'s fingerprint this covers the whole request, the client token, session and `doNotStore` included,
+/// because followers get the leader's response as it is and only one of them is sent.
+pub(crate) fn key(req: &crate::types::Request) -> Option<String> {
+    serde_json::to_value(req).ok().map(|value| value.to_string())
+}
+
+/// Requests that arrived while an identical one was already waiting or in flight, and get its response instead of
+/// being sent themselves.
+pub(crate) struct Followers<T> {
+    waiting: HashMap<String, Vec<T>>,
+}
+
+impl<T> Followers<T> {
+    pub(crate) fn new() -> Self {
+        Self { waiting: HashMap::new() }
+    }
+
+    /// Start tracking a request that was just queued, so identical ones can follow it.
+    pub(crate) fn lead(&mut self, key: String, followers: Vec<T>) {
+        self.waiting.insert(key, followers);
+    }
+
+    /// The requests following the one with `key`, `None` if no such request is waiting or in flight.
+    pub(crate) fn of(&mut self, key: &str) -> Option<&mut Vec<T>> {
+        self.waiting.get_mut(key)
+    }
+
+    /// Stop tracking the request with `key`, returning everything that followed it.
+    pub(crate) fn finish(&mut self, key: &str) -> Vec<T> {
+        self.waiting.remove(key).unwrap_or_default()
+    }
+
+    /// Take the first follower matching `f`.
+    pub(crate) fn remove_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Option<T> {
+        self.waiting.values_mut().find_map(|followers| {
+            let i = followers.iter().position(&mut f)?;
+            Some(followers.remove(i))
+        })
+    }
+
+    /// Take every follower matching `f`.
+    pub(crate) fn take_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut matching = Vec::new();
+        for followers in self.waiting.values_mut() {
+            let (taken, kept) = std::mem::take(followers).into_iter().partition::<Vec<_>, _>(&mut f);
+            *followers = kept;
+            matching.extend(taken);
+        }
+        matching
+    }
+
+    /// Take every follower and stop tracking everything.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.waiting.drain().flat_map(|(_, followers)| followers)
+    }
+}
+
+/// Copy the response of a request for `n` waiters, errors can't be cloned so they are shared.
+pub(crate) fn share(res: crate::types::Response, n: usize) -> Vec<crate::types::Response> {
+    match res {
+        Ok(res) => vec![res; n].into_iter().map(Ok).collect(),
+        Err(e) if n > 1 => {
+            let e = std::sync::Arc::new(e);
+            (0..n).map(|_| Err(ApiError::Shared(e.clone()))).collect()
+        }
+        Err(e) => vec![Err(e)],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::AttributeOptions;
+
+    #[test]
+    fn test_key_ignores_attribute_order() {
+        let request = || crate::types::RequestBuilder::default().comment("lol").all_attributes().build().unwrap();
+
+        assert_eq!(key(&request()), key(&request()));
+
+        let mut attributes = crate::types::Attribute::all();
+        let forward = crate::types::RequestBuilder::default().comment("lol").requested_attributes(attributes.iter().cloned().map(|a| (a, AttributeOptions::default())).collect::<HashMap<_, _>>()).build().unwrap();
+        attributes.reverse();
+        let mut backward = crate::types::RequestBuilder::default();
+        backward.comment("lol");
+        for attribute in attributes {
+            backward.add_attribute(attribute, AttributeOptions::default());
+        }
+        assert_eq!(key(&forward), key(&backward.build().unwrap()));
+        assert_ne!(key(&forward), key(&crate::types::RequestBuilder::default().comment("lmao").all_attributes().build().unwrap()));
+    }
+
+    #[test]
+    fn test_followers() {
+        let mut followers = Followers::new();
+        followers.lead("a".into(), Vec::new());
+        followers.of("a").unwrap().extend([1, 2, 3]);
+        followers.lead("b".into(), vec![4]);
+
+        assert!(followers.of("c").is_none());
+        assert_eq!(followers.remove_where(|n| *n == 2), Some(2));
+        let mut taken = followers.take_where(|n| *n > 2);
+        taken.sort();
+        assert_eq!(taken, [3, 4]);
+        assert_eq!(followers.finish("a"), [1]);
+        assert_eq!(followers.drain().count(), 0);
+    }
+
+    #[test]
+    fn test_share() {
+        let shared = share(Err(ApiError::QueueFull), 3);
+        assert_eq!(shared.len(), 3);
+        assert!(shared.iter().all(|r| matches!(r, Err(ApiError::Shared(e)) if matches!(**e, ApiError::QueueFull))));
+
+        assert!(matches!(share(Err(ApiError::QueueFull), 1).as_slice(), [Err(ApiError::QueueFull)]));
+    }
+}
@@ -0,0 +1,339 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::types::{ApiError, Priority, RequestId, RequestWithPriority, Response};
+
+/// What a client lends the [`Dispatcher`]: room in the queues and somewhere to deliver responses.
+///
+/// Both depend on the runtime the client waits on, everything else that happens to a request between being accepted
+/// and being answered is the same for both clients.
+pub(crate) trait Outlet {
+    /// Room for one request in the queues, given back when dropped.
+    type Slot;
+    /// Where the response to a request with a ticket goes.
+    type Responder;
+
+    /// Take room at `priority` for `tenant` if there is some, without waiting for it.
+    fn try_acquire(&self, priority: Priority, tenant: Option<String>) -> Option<Self::Slot>;
+    /// Hand a response to its ticket if it has a `responder`, otherwise to the shared response channel along with the
+    /// room its request held. Must not wait, or a slow consumer would hold up the background thread.
+    fn deliver(&self, responder: Option<Self::Responder>, slot: Option<Self::Slot>, res: Response);
+}
+
+/// A request waiting to be picked up by the background thread, along with where its response should go.
+pub(crate) struct Queued<O: Outlet> {
+    pub(crate) id: RequestId,
+    pub(crate) request: RequestWithPriority,
+    /// `None` for requests sent with `send_*`, whose responses go to the shared response channel.
+    pub(crate) responder: Option<O::Responder>,
+    /// How many times this request has been sent to the API so far.
+    pub(crate) attempts: u32,
+    pub(crate) deadline: Option<Instant>,
+    /// Explicitly set or taken from the request's `community_id`.
+    pub(crate) tenant: Option<String>,
+    /// Held while the request is waiting to be sent, so producers can wait for room in the queues.
+    pub(crate) slot: Option<O::Slot>,
+    /// What identical requests are coalesced by, `None` when [`crate::ClientConfig::coalesce_requests`] is off.
+    pub(crate) key: Option<String>,
+    pub(crate) bypass_cache: bool,
+}
+
+impl<O: Outlet> Queued<O> {
+    pub(crate) fn new(id: RequestId, req: crate::types::Request, options: crate::types::RequestOptions, responder: Option<O::Responder>, slot: O::Slot) -> Self {
+        Self {
+            id,
+            tenant: options.tenant_of(&req),
+            request: RequestWithPriority::new(req, options.priority),
+            responder,
+            attempts: 0,
+            deadline: options.deadline,
+            slot: Some(slot),
+            key: None,
+            bypass_cache: options.bypass_cache,
+        }
+    }
+}
+
+/// Everything the background thread keeps track of between accepting a request and answering it: the queues, the
+/// retries, the coalesced requests, the cache and the journal.
+///
+/// The clients only drive it, deciding when to wait, when to send and what to do with the transport.
+pub(crate) struct Dispatcher<O: Outlet> {
+    outlet: O,
+    journal: Option<Arc<crate::journal::JournalFile>>,
+    queues: crate::queue::PriorityQueues<Queued<O>>,
+    retrying: crate::retry::Backoffs<Queued<O>>,
+    followers: crate::coalesce::Followers<Queued<O>>,
+    /// The deadline of every request that has one, soonest first. Requests that were sent or answered in the meantime
+    /// are only dropped from here once their deadline comes up.
+    deadlines: BinaryHeap<Reverse<(Instant, RequestId)>>,
+    cache: Option<crate::cache::ResponseCache>,
+    coalesce_requests: bool,
+    retry_policy: crate::RetryPolicy,
+}
+
+impl<O: Outlet> Dispatcher<O> {
+    /// Fails only if the thread writing the [`crate::Cache`] to disk can't be spawned.
+    pub(crate) fn new(config: &crate::ClientConfig, outlet: O, journal: Option<Arc<crate::journal::JournalFile>>, cache_stats: crate::cache::SharedStats) -> std::io::Result<Self> {
+        Ok(Self {
+            outlet,
+            journal,
+            queues: crate::queue::PriorityQueues::new(config.maximum_queue_size, config.level_queue_sizes.clone(), config.scheduling.clone(), config.fairness.clone()),
+            retrying: crate::retry::Backoffs::new(),
+            followers: crate::coalesce::Followers::new(),
+            deadlines: BinaryHeap::new(),
+            cache: config.cache.as_ref().map(|c| crate::cache::ResponseCache::open(c, cache_stats)).transpose()?,
+            coalesce_requests: config.coalesce_requests,
+            retry_policy: config.retry_policy.clone(),
+        })
+    }
+
+    /// Answer a new request from the cache, let it follow an identical one or queue it.
+    pub(crate) fn accept(&mut self, mut queued: Queued<O>) {
+        log::info!("received request");
+
+        if self.coalesce_requests {
+            queued.key = crate::coalesce::key(&queued.request);
+        }
+        // answered straight away, without using any of the rate
+        let cached = match self.cache.as_mut() {
+            Some(cache) if !queued.bypass_cache => cache.get(&queued.request, std::time::SystemTime::now()),
+            _ => None,
+        };
+        if let Some(res) = cached {
+            log::info!("answering request {} from the cache", queued.id);
+            self.respond(queued, Ok(res));
+            return;
+        }
+
+        if let Some(deadline) = queued.deadline {
+            self.deadlines.push(Reverse((deadline, queued.id)));
+        }
+        if let Some(key) = queued.key.clone().filter(|key| self.followers.of(key).is_some()) {
+            self.follow(key, queued);
+        } else {
+            let priority = queued.request.priority();
+            let key = queued.key.clone();
+            match self.queues.push(priority, queued.tenant.clone(), queued, Instant::now()) {
+                Ok(()) => {
+                    if let Some(key) = key {
+                        self.followers.lead(key, Vec::new());
+                    }
+                }
+                Err(queued) => {
+                    log::info!("priority {} queue is full", priority);
+                    self.respond(queued, Err(ApiError::QueueFull));
+                }
+            }
+        }
+    }
+
+    /// Withdraw a request that is still waiting, returning whether it was.
+    pub(crate) fn cancel(&mut self, id: RequestId) -> bool {
+        let mut cancelled = self.followers.remove_where(|q| q.id == id);
+        if cancelled.is_none() {
+            cancelled = self.queues.remove_where(|q| q.id == id).or_else(|| self.retrying.remove_where(|q| q.id == id));
+            if let Some(leader) = cancelled.as_mut() {
+                self.hand_over(leader);
+            }
+        }
+        let Some(queued) = cancelled else {
+            return false;
+        };
+        log::info!("cancelled request {}", id);
+        self.respond(queued, Err(ApiError::Cancelled(id)));
+        true
+    }
+
+    /// Answer every waiting request whose deadline has passed, without sending it.
+    pub(crate) fn drop_expired(&mut self) {
+        let now = Instant::now();
+        let mut ids = HashSet::new();
+        while let Some(&Reverse((deadline, id))) = self.deadlines.peek() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop();
+            ids.insert(id);
+        }
+        if ids.is_empty() {
+            return;
+        }
+        let is_expired = |q: &Queued<O>| ids.contains(&q.id);
+        // followers go first, so none of them is handed an expired request's place
+        let mut expired = self.followers.take_where(is_expired);
+        let mut leaders = self.queues.take_where(is_expired);
+        leaders.extend(self.retrying.take_where(is_expired));
+        for leader in &mut leaders {
+            self.hand_over(leader);
+        }
+        expired.extend(leaders);
+        for queued in expired {
+            let id = queued.id;
+            log::info!("request {} expired", id);
+            self.respond(queued, Err(ApiError::Expired(id)));
+        }
+    }
+
+    /// Put the retries that are due back at the end of their original priority queue, so they are rate limited like
+    /// everything else.
+    pub(crate) fn requeue_due(&mut self, now: Instant) {
+        for (e, mut queued) in self.retrying.take_due(now) {
+            let priority = queued.request.priority();
+            let slot = queued.slot.take().or_else(|| self.outlet.try_acquire(priority, queued.tenant.clone()));
+            let pushed = match slot {
+                Some(slot) => {
+                    queued.slot = Some(slot);
+                    self.queues.push(priority, queued.tenant.clone(), queued, now)
+                }
+                None => Err(queued),
+            };
+            if let Err(queued) = pushed {
+                log::info!("priority {} queue is full, giving up on retry", priority);
+                let res = crate::retry::with_attempts(Err(e), queued.attempts);
+                self.respond_all(queued, res);
+            }
+        }
+    }
+
+    /// How long until the next request may be sent as far as the queues and tenants are concerned, `None` if they
+    /// are empty.
+    pub(crate) fn delay(&mut self, now: Instant) -> Option<Duration> {
+        self.queues.delay(now)
+    }
+
+    /// Take the next request to send.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<Queued<O>> {
+        let (priority, mut queued) = self.queues.pop(now)?;
+        log::info!("sending priority {} request", priority);
+        // its room in the queues is free as soon as it leaves them, unless its response goes to the shared channel
+        if queued.responder.is_some() {
+            queued.slot = None;
+        }
+        Some(queued)
+    }
+
+    /// Retry a request that came back from the API, or answer it along with everything that followed it.
+    pub(crate) fn settle(&mut self, res: Response, mut queued: Queued<O>, rate_limiter: &mut crate::rate_limit::RateLimiter) {
+        queued.attempts += 1;
+        match crate::retry::settle(res, queued.attempts, &self.retry_policy, rate_limiter) {
+            crate::retry::Settled::Retry { delay, error } => self.retrying.push(delay, error, queued),
+            crate::retry::Settled::Done(res) => {
+                if let (Some(cache), Ok(res)) = (self.cache.as_mut(), &res) {
+                    cache.put(&queued.request, res, std::time::SystemTime::now());
+                }
+                self.respond_all(queued, res);
+            }
+        }
+    }
+
+    /// Everything that was accepted but not sent, including the requests that `arrived` after the last look at the
+    /// client's channel, highest priority first.
+    pub(crate) fn take_unsent(&mut self, arrived: impl IntoIterator<Item = Queued<O>>) -> Vec<RequestWithPriority> {
+        let mut unsent = self.queues.drain().chain(self.retrying.drain()).chain(self.followers.drain()).chain(arrived).map(|q| q.request).collect::<Vec<_>>();
+        // requests still in the channel arrived in any order
+        unsent.sort_by_key(|r| std::cmp::Reverse(r.priority()));
+        unsent
+    }
+
+    /// Whether nothing is waiting to be sent or retried.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.queues.is_empty() && self.retrying.is_empty()
+    }
+
+    /// When the next retry is due.
+    pub(crate) fn next_retry(&self) -> Option<Instant> {
+        self.retrying.next_due()
+    }
+
+    /// When the next waiting request expires, or one that has been sent or answered since.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.deadlines.peek().map(|Reverse((deadline, _))| *deadline)
+    }
+
+    /// Let a request follow the identical one with `key` that is already waiting or in flight.
+    ///
+    /// If that one is still queued at a lower priority the new request takes its place and it follows instead, so an
+    /// urgent request never waits behind a less urgent copy of itself.
+    fn follow(&mut self, key: String, mut queued: Queued<O>) {
+        let priority = queued.request.priority();
+        if let Some(mut leader) = self.queues.remove_where(|q| q.key.as_deref() == Some(key.as_str()) && q.request.priority() < priority) {
+            log::info!("request {} takes the place of an identical lower priority one", queued.id);
+            // it holds room of its own at its priority, which the leader gives up
+            match self.queues.push(priority, queued.tenant.clone(), queued, Instant::now()) {
+                Ok(()) => {
+                    leader.slot = None;
+                    queued = leader;
+                }
+                Err(follower) => {
+                    let _ = self.queues.push(leader.request.priority(), leader.tenant.clone(), leader, Instant::now());
+                    queued = follower;
+                }
+            }
+        }
+        log::info!("coalescing request {} with an identical one", queued.id);
+        // it is never sent itself, so it doesn't need room in the queues
+        queued.slot = None;
+        if let Some(waiting) = self.followers.of(&key) {
+            waiting.push(queued);
+        }
+    }
+
+    /// Let the requests following one that leaves without an answer carry on without it, the first of them takes its place.
+    fn hand_over(&mut self, leader: &mut Queued<O>) {
+        let Some(key) = leader.key.as_deref() else {
+            return;
+        };
+        let mut waiting = self.followers.finish(key).into_iter();
+        // the follower may have another priority or tenant, so it takes room of its own in place of the leader's
+        leader.slot = None;
+        while let Some(mut next) = waiting.next() {
+            let priority = next.request.priority();
+            let pushed = match self.outlet.try_acquire(priority, next.tenant.clone()) {
+                Some(slot) => {
+                    next.slot = Some(slot);
+                    self.queues.push(priority, next.tenant.clone(), next, Instant::now())
+                }
+                None => Err(next),
+            };
+            match pushed {
+                Ok(()) => {
+                    self.followers.lead(key.to_owned(), waiting.collect());
+                    return;
+                }
+                Err(next) => {
+                    log::info!("priority {} queue is full", priority);
+                    self.respond(next, Err(ApiError::QueueFull));
+                }
+            }
+        }
+    }
+
+    /// Answer a request that was sent to the API along with every identical request that followed it.
+    ///
+    /// They all went out as that one request, so if it failed for good every one of them goes to the dead letters.
+    fn respond_all(&mut self, queued: Queued<O>, res: Response) {
+        let waiting = queued.key.as_deref().map(|key| self.followers.finish(key)).unwrap_or_default();
+        let shared = crate::coalesce::share(res, waiting.len() + 1);
+        for (queued, res) in std::iter::once(queued).chain(waiting).zip(shared) {
+            if let (Some(journal), Err(e)) = (self.journal.as_ref(), &res) {
+                journal.fail(queued.id, e);
+            }
+            self.respond(queued, res);
+        }
+    }
+
+    /// Deliver a response through the [`Outlet`].
+    ///
+    /// The answer is handed to the journal's writer without waiting for the disk, so it lands after the request's dead
+    /// letter but may still be on its way when the response arrives. A crash in between means the request is picked up
+    /// again, [`crate::Journal`] delivers at least once. Shutting the client down waits for the journal to catch up.
+    fn respond(&self, queued: Queued<O>, res: Response) {
+        if let Some(journal) = self.journal.as_ref() {
+            journal.answer(queued.id);
+        }
+        self.outlet.deliver(queued.responder, queued.slot, res);
+    }
+}
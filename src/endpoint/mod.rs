@@ -0,0 +1,77 @@
+use crate::ClientConfig;
+
+/// Where requests are sent and what is sent along with them, built once from the [`ClientConfig`].
+#[derive(Debug)]
+pub(crate) struct Endpoint {
+    pub(crate) url: String,
+    pub(crate) headers: reqwest::header::HeaderMap,
+}
+
+impl Endpoint {
+    pub(crate) fn new(config: &ClientConfig) -> Self {
+        // curl -H "Content-Type: application/json" -H "X-Goog-Api-Key: YOUR_KEY_HERE" --data \
+        //     '{comment: {text: "what kind of idiot name is foo?"},
+        //        languages: ["en"],
+        //        requestedAttributes: {TOXICITY:{}} }' \
+        // https://commentanalyzer.googleapis.com/v1alpha1/comments:analyze
+        let url = format!("{}/{}/comments:analyze", config.base_url.trim_end_matches('/'), config.api_version.trim_matches('/'));
+
+        // the builder already checked that these parse
+        let headers = config
+            .headers
+            .iter()
+            .filter_map(|(name, value)| Some((name.parse::<reqwest::header::HeaderName>().ok()?, value.parse::<reqwest::header::HeaderValue>().ok()?)))
+            .fold(reqwest::header::HeaderMap::new(), |mut map, (name, value)| {
+                map.append(name, value);
+                map
+            });
+
+        // the key goes in a header so it can't leak through the url in errors or proxy logs
+        let mut headers = headers;
+        match reqwest::header::HeaderValue::from_str(config.api_key.expose()) {
+            Ok(mut key) => {
+                key.set_sensitive(true);
+                headers.insert("x-goog-api-key", key);
+            }
+            Err(_) => log::error!("api key is not a valid header value"),
+        }
+
+        Self { url, headers }
+    }
+}
+
+pub(crate) fn validate_base_url(base_url: &str) -> Result<(), String> {
+    match reqwest::Url::parse(base_url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        Ok(_) => Err("base url must be http or https".into()),
+        Err(e) => Err(format!("invalid base url: {}", e)),
+    }
+}
+
+pub(crate) fn validate_header(name: &str, value: &str) -> Result<(), String> {
+    name.parse::<reqwest::header::HeaderName>().map_err(|e| format!("invalid header name {:?}: {}", name, e))?;
+    value.parse::<reqwest::header::HeaderValue>().map_err(|e| format!("invalid value for header {:?}: {}", name, e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_endpoint_from_config() {
+        let config = crate::ClientConfigBuilder::default()
+            .api_key("secret-key".into())
+            .base_url("http://127.0.0.1:8080/")
+            .api_version("/v1/")
+            .header("X-Goog-User-Project", "my-project")
+            .build()
+            .unwrap();
+        let endpoint = Endpoint::new(&config);
+
+        assert_eq!(endpoint.url, "http://127.0.0.1:8080/v1/comments:analyze");
+        assert_eq!(endpoint.headers.get("x-goog-user-project").unwrap(), "my-project");
+        assert_eq!(endpoint.headers.get("x-goog-api-key").unwrap(), "secret-key");
+        assert!(!format!("{:?}", endpoint).contains("secret-key"));
+    }
+}
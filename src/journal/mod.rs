@@ -0,0 +1,439 @@
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::types::{ApiError, Priority, Request, RequestId, RequestOptions};
+
+/// How many lines the journal can have on top of the pending requests before it is rewritten.
+const COMPACT_SLACK: usize = 1024;
+
+/// Write every accepted request to a file before acknowledging it, so requests that were never answered are picked up
+/// again when a client is created with the same journal, after a crash or a restart.
+///
+/// The journal is a JSON lines file of accepted requests, with their priority, tenant, deadline and cache option, and of
+/// the ids of requests that have been answered. Requests picked up again have no ticket, their responses go to the
+/// shared response channel, so give requests a `client_token` to tell them apart. Delivery is at least once: a request
+/// that was answered just before a crash is sent and answered again, and requests handed back by `shutdown` are still
+/// picked up again unless they are answered some other way.
+///
+/// Requests that were sent and failed for good, because the API rejected them or they ran out of retries, are also
+/// written to `dead_letter_path` along with the error, so they can be looked into or sent again by hand. Requests that
+/// never went out, because the queues were full or they were cancelled or expired, are only answered. The files should only be used by one client at a time.
+#[derive(derive_builder::Builder, Clone, Debug, PartialEq, Eq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct Journal {
+    #[builder(setter(into))]
+    pub path: PathBuf,
+    #[builder(setter(into))]
+    pub dead_letter_path: PathBuf,
+}
+
+impl Journal {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        for path in [&self.path, &self.dead_letter_path] {
+            if path.is_dir() {
+                return Err(format!("journal path {} is a directory", path.display()));
+            }
+        }
+
+        if self.path == self.dead_letter_path {
+            return Err("journal and dead letters cannot share a file".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl JournalBuilder {
+    fn validate(&self) -> Result<(), String> {
+        match (self.path.as_ref(), self.dead_letter_path.as_ref()) {
+            (Some(path), Some(dead_letter_path)) => Journal {
+                path: path.clone(),
+                dead_letter_path: dead_letter_path.clone(),
+            }
+            .validate(),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// An accepted request, as it is written to the journal.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub(crate) struct Record {
+    pub(crate) id: u64,
+    pub(crate) request: Request,
+    pub(crate) priority: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) tenant: Option<String>,
+    /// In milliseconds since the unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) deadline: Option<u64>,
+    #[serde(default)]
+    pub(crate) bypass_cache: bool,
+}
+
+impl Record {
+    pub(crate) fn new(id: RequestId, request: &Request, options: &RequestOptions) -> Self {
+        Self {
+            id: id.0,
+            request: request.clone(),
+            priority: options.priority.0,
+            tenant: options.tenant.clone(),
+            deadline: options.deadline.map(|deadline| {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                millis(SystemTime::now() + remaining)
+            }),
+            bypass_cache: options.bypass_cache,
+        }
+    }
+
+    /// The options the request was sent with, a deadline that passed while the client was down is now.
+    pub(crate) fn options(&self) -> RequestOptions {
+        RequestOptions {
+            priority: Priority(self.priority),
+            deadline: self.deadline.map(|deadline| Instant::now() + Duration::from_millis(deadline.saturating_sub(millis(SystemTime::now())))),
+            tenant: self.tenant.clone(),
+            bypass_cache: self.bypass_cache,
+        }
+    }
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// One line of the journal.
+// lines only live long enough to be read or written, boxing records would only add an allocation
+#[allow(clippy::large_enum_variant)]
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Line {
+    Accepted(Record),
+    Answered { id: u64 },
+}
+
+/// A request that failed for good, as it is written to the dead letters.
+#[derive(serde::Serialize)]
+struct DeadLetter<'a> {
+    #[serde(flatten)]
+    record: &'a Record,
+    error: String,
+    /// In milliseconds since the unix epoch.
+    failed: u64,
+}
+
+/// An open journal, shared by the client and its background thread.
+///
+/// The files are written by a thread of their own, in the order requests are accepted and answered. Accepting a request
+/// waits for the writer to have it on disk, answers and dead letters are only handed to the writer, so the background
+/// thread never waits on the disk. Writing is best effort: if the journal can't be written the request is still sent,
+/// it just isn't picked up again.
+pub(crate) struct JournalFile {
+    writes: std::sync::mpsc::Sender<Job>,
+    /// What was left unanswered when the journal was opened.
+    pending: Vec<Record>,
+    next_id: u64,
+}
+
+/// What the writer thread is asked to do, see [`JournalFile`].
+// almost every write is an accepted request, boxing them would only add an allocation
+#[allow(clippy::large_enum_variant)]
+enum Job {
+    /// Answered once the request is on disk.
+    Accept(Record, futures::channel::oneshot::Sender<()>),
+    Fail { id: u64, error: String, failed: u64 },
+    Answer(u64),
+    /// Answered once everything sent before it has been written.
+    Flush(futures::channel::oneshot::Sender<()>),
+}
+
+struct Inner {
+    path: PathBuf,
+    file: Option<std::fs::File>,
+    /// The number of lines in the file.
+    lines: usize,
+    pending: BTreeMap<u64, Record>,
+    dead_letter_path: PathBuf,
+    dead_letters: Option<std::fs::File>,
+}
+
+impl JournalFile {
+    /// Load the journal and start its writer thread, which fails only if the thread can't be spawned.
+    pub(crate) fn open(config: &Journal) -> std::io::Result<Self> {
+        let mut pending = BTreeMap::new();
+        let mut next_id = 0;
+        match std::fs::File::open(&config.path) {
+            Ok(file) => {
+                // a line cut short by a crash is skipped, it was never acknowledged
+                for line in std::io::BufReader::new(file).lines().map_while(Result::ok) {
+                    match serde_json::from_str::<Line>(&line) {
+                        Ok(Line::Accepted(record)) => {
+                            next_id = next_id.max(record.id + 1);
+                            pending.insert(record.id, record);
+                        }
+                        Ok(Line::Answered { id }) => {
+                            next_id = next_id.max(id + 1);
+                            pending.remove(&id);
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => log::error!("failed to load journal {}: {}", config.path.display(), e),
+        }
+        log::info!("picking up {} requests from journal {}", pending.len(), config.path.display());
+
+        let dead_letters = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.dead_letter_path)
+            .map_err(|e| log::error!("failed to open dead letters {}: {}", config.dead_letter_path.display(), e))
+            .ok();
+        let mut inner = Inner {
+            path: config.path.clone(),
+            file: None,
+            lines: 0,
+            pending,
+            dead_letter_path: config.dead_letter_path.clone(),
+            dead_letters,
+        };
+        inner.compact();
+
+        let pending = inner.pending.values().cloned().collect();
+        let (writes, jobs) = std::sync::mpsc::channel();
+        // the writer stops once every sender is gone, after finishing what it was given
+        std::thread::Builder::new().name("perspective-rs-journal-writer".into()).spawn(move || inner.run(jobs))?;
+        Ok(Self { writes, pending, next_id })
+    }
+
+    /// The id to start counting from, so ids in the journal are never reused by requests that are still in it.
+    pub(crate) fn next_id(&self) -> u64 {
+        self.next_id
+    }
+
+    /// Every request that was accepted but never answered when the journal was opened, oldest first.
+    pub(crate) fn pending(&self) -> Vec<Record> {
+        self.pending.clone()
+    }
+
+    /// Write down an accepted request, this resolves once it has reached the disk.
+    pub(crate) async fn accept(&self, record: Record) {
+        let (written, done) = futures::channel::oneshot::channel();
+        self.send(Job::Accept(record, written));
+        let _ = done.await;
+    }
+
+    /// Write a request that was sent and failed for good to the dead letters, ahead of its answer.
+    pub(crate) fn fail(&self, id: RequestId, error: &ApiError) {
+        self.send(Job::Fail {
+            id: id.0,
+            error: error.to_string(),
+            failed: millis(SystemTime::now()),
+        });
+    }
+
+    /// Write down that a request has been answered, or handed back to the caller, so it isn't picked up again.
+    ///
+    /// This only hands the answer to the writer thread. Until it is on disk a crash means the request is picked up
+    /// again, which at least once delivery allows for.
+    pub(crate) fn answer(&self, id: RequestId) {
+        self.send(Job::Answer(id.0));
+    }
+
+    /// Resolves once everything asked for so far is on disk.
+    pub(crate) async fn flush(&self) {
+        let (flushed, done) = futures::channel::oneshot::channel();
+        self.send(Job::Flush(flushed));
+        let _ = done.await;
+    }
+
+    fn send(&self, job: Job) {
+        if self.writes.send(job).is_err() {
+            log::error!("failed to write journal: the writer thread has stopped");
+        }
+    }
+}
+
+impl Inner {
+    /// Carry out writes until the [`JournalFile`] is dropped.
+    fn run(mut self, jobs: std::sync::mpsc::Receiver<Job>) {
+        for job in jobs {
+            match job {
+                Job::Accept(record, written) => {
+                    self.write(&Line::Accepted(record.clone()));
+                    self.pending.insert(record.id, record);
+                    let _ = written.send(());
+                }
+                Job::Fail { id, error, failed } => {
+                    if let Some(record) = self.pending.get(&id).cloned() {
+                        self.dead_letter(&DeadLetter { record: &record, error, failed });
+                    }
+                }
+                Job::Answer(id) => {
+                    if self.pending.remove(&id).is_none() {
+                        continue;
+                    }
+                    self.write(&Line::Answered { id });
+
+                    if self.lines > 2 * self.pending.len() + COMPACT_SLACK {
+                        self.compact();
+                    }
+                }
+                Job::Flush(flushed) => {
+                    let _ = flushed.send(());
+                }
+            }
+        }
+    }
+
+    fn write(&mut self, line: &Line) {
+        let Some(file) = self.file.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_vec(line).map_err(std::io::Error::from).and_then(|mut bytes| {
+            bytes.push(b'\n');
+            file.write_all(&bytes)?;
+            file.sync_data()
+        });
+        match written {
+            Ok(()) => self.lines += 1,
+            Err(e) => log::error!("failed to write journal {}: {}", self.path.display(), e),
+        }
+    }
+
+    fn dead_letter(&mut self, letter: &DeadLetter) {
+        let Some(file) = self.dead_letters.as_mut() else {
+            return;
+        };
+        let written = serde_json::to_vec(letter).map_err(std::io::Error::from).and_then(|mut bytes| {
+            bytes.push(b'\n');
+            file.write_all(&bytes)?;
+            file.sync_data()
+        });
+        if let Err(e) = written {
+            log::error!("failed to write dead letters {}: {}", self.dead_letter_path.display(), e);
+        }
+    }
+
+    /// Rewrite the file with only the pending requests.
+    fn compact(&mut self) {
+        self.file = None;
+        // appended rather than swapped for the extension, so files that only differ in their extension don't share it
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let written = std::fs::File::create(&temporary)
+            .and_then(|file| {
+                let mut writer = std::io::BufWriter::new(file);
+                for record in self.pending.values() {
+                    serde_json::to_writer(&mut writer, &Line::Accepted(record.clone()))?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+            })
+            .and_then(|()| std::fs::rename(&temporary, &self.path))
+            .and_then(|()| std::fs::OpenOptions::new().append(true).open(&self.path));
+        match written {
+            Ok(file) => {
+                self.file = Some(file);
+                self.lines = self.pending.len();
+            }
+            Err(e) => log::error!("failed to write journal {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+/// Where the journal for a test is kept, removed again when dropped.
+#[cfg(test)]
+pub(crate) struct TestJournal(pub(crate) Journal);
+
+#[cfg(test)]
+impl TestJournal {
+    pub(crate) fn new(name: &str) -> Self {
+        let path = |suffix: &str| std::env::temp_dir().join(format!("perspective-rs-{}-{}-{}.jsonl", name, suffix, std::process::id()));
+        Self(Journal {
+            path: path("journal"),
+            dead_letter_path: path("dead"),
+        })
+    }
+
+    pub(crate) fn dead_letters(&self) -> Vec<serde_json::Value> {
+        std::fs::read_to_string(&self.0.dead_letter_path)
+            .unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+impl Drop for TestJournal {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0.path);
+        let _ = std::fs::remove_file(&self.0.dead_letter_path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Attribute, AttributeOptions, RequestBuilder};
+    use futures::executor::block_on;
+
+    fn request(text: &str) -> Request {
+        RequestBuilder::default().comment(text).add_attribute(Attribute::Toxicity, AttributeOptions::default()).build().unwrap()
+    }
+
+    #[test]
+    fn test_pending_requests_survive_reopening() {
+        let journal = TestJournal::new("reopen");
+        {
+            let file = JournalFile::open(&journal.0).unwrap();
+            let options = RequestOptions::new(Priority::HIGH).tenant("a").ttl(Duration::from_secs(60));
+            block_on(file.accept(Record::new(RequestId(0), &request("answered"), &options)));
+            block_on(file.accept(Record::new(RequestId(1), &request("failed"), &options)));
+            block_on(file.accept(Record::new(RequestId(2), &request("pending"), &options)));
+            file.answer(RequestId(0));
+            file.fail(RequestId(1), &ApiError::CommentEmpty);
+            file.answer(RequestId(1));
+            block_on(file.flush());
+        }
+
+        let file = JournalFile::open(&journal.0).unwrap();
+        let pending = file.pending();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].request.comment.text, "pending");
+        let options = pending[0].options();
+        assert_eq!(options.priority, Priority::HIGH);
+        assert_eq!(options.tenant.as_deref(), Some("a"));
+        assert!(options.deadline.unwrap() > Instant::now() + Duration::from_secs(50));
+        assert_eq!(file.next_id(), 3);
+
+        let dead_letters = journal.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0]["id"], 1);
+        assert_eq!(dead_letters[0]["error"], "Comment must be non-empty.");
+    }
+
+    #[test]
+    fn test_torn_line_is_skipped() {
+        let journal = TestJournal::new("torn");
+        {
+            let file = JournalFile::open(&journal.0).unwrap();
+            block_on(file.accept(Record::new(RequestId(0), &request("kept"), &RequestOptions::default())));
+        }
+        let mut file = std::fs::OpenOptions::new().append(true).open(&journal.0.path).unwrap();
+        file.write_all(b"{\"op\":\"accepted\",\"id\":1,\"requ").unwrap();
+
+        assert_eq!(JournalFile::open(&journal.0).unwrap().pending().len(), 1);
+    }
+
+    #[test]
+    fn test_journal_builder_validate() {
+        assert!(JournalBuilder::default().path("a.jsonl").dead_letter_path("a.jsonl").build().is_err());
+        assert!(JournalBuilder::default().path(std::env::temp_dir()).dead_letter_path("b.jsonl").build().is_err());
+        assert!(JournalBuilder::default().path("a.jsonl").build().is_err());
+        assert!(JournalBuilder::default().path("a.jsonl").dead_letter_path("b.jsonl").build().is_ok());
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+use crate::rate_limit::{RateLimit, RateLimiter, SharedRate};
+use crate::types::Priority;
+
+/// How the client picks which priority level to send from next.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum Scheduling {
+    /// Always send the highest priority request, lower priorities only get a turn when nothing above them is waiting.
+    #[default]
+    Strict,
+    /// Share the rate between the levels that have requests waiting in proportion to their weights, levels that
+    /// aren't listed weigh 1. E.g. `HIGH: 4, NORMAL: 2, LOW: 1` sends 4 high priority requests for every normal one and
+    /// 2 normal ones for every low one while all three are busy.
+    WeightedRoundRobin { weights: BTreeMap<Priority, u32> },
+    /// Like [`Scheduling::Strict`], but a request's priority goes up by one for every `step` it has been waiting, so
+    /// a busy level can hold back a lower one for at most `step` times the difference between them.
+    Aging { step: Duration },
+}
+
+impl Scheduling {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            Scheduling::WeightedRoundRobin { weights } if weights.values().any(|w| *w == 0) => Err("scheduling weights cannot be 0".into()),
+            Scheduling::Aging { step } if step.is_zero() => Err("aging step cannot be 0".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn weight(&self, priority: Priority) -> i64 {
+        match self {
+            Scheduling::WeightedRoundRobin { weights } => weights.get(&priority).copied().unwrap_or(1) as i64,
+            _ => 1,
+        }
+    }
+}
+
+/// Round robin between tenants within each priority level, so one busy tenant can't hold everyone else back.
+///
+/// A request's tenant is [`crate::RequestOptions::tenant`], or its `community_id` if that isn't set. Requests without
+/// either share one anonymous tenant that isn't subject to the per-tenant limits.
+#[derive(derive_builder::Builder, Clone, Debug, Default, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct Fairness {
+    /// The maximum number of requests a single tenant can have waiting, across all priorities.
+    #[builder(default, setter(strip_option))]
+    pub tenant_queue_size: Option<usize>,
+    /// How fast a single tenant's requests are sent, on top of the client's own [`crate::ClientConfig::rate_limit`].
+    #[builder(default, setter(strip_option))]
+    pub tenant_rate_limit: Option<RateLimit>,
+}
+
+impl Fairness {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if let Some(0) = self.tenant_queue_size {
+            return Err("tenant queue size cannot be 0".into());
+        }
+
+        if let Some(rate_limit) = self.tenant_rate_limit.as_ref() {
+            rate_limit.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FairnessBuilder {
+    fn validate(&self) -> Result<(), String> {
+        Fairness {
+            tenant_queue_size: self.tenant_queue_size.flatten(),
+            tenant_rate_limit: self.tenant_rate_limit.clone().flatten(),
+        }
+        .validate()
+    }
+}
+
+type Tenant = Option<String>;
+
+/// How much room is left in the queues as a whole, at each priority level and for each tenant.
+///
+/// Room is taken when a request is accepted rather than when it reaches the queues, so whoever sends a request finds
+/// out straight away that its level or tenant is full. The clients keep this behind a lock, along with a way to wake
+/// whoever is waiting for room.
+pub(crate) struct Capacity {
+    capacity: usize,
+    level_capacities: BTreeMap<Priority, usize>,
+    tenant_capacity: Option<usize>,
+    len: usize,
+    level_lens: BTreeMap<Priority, usize>,
+    tenant_lens: HashMap<String, usize>,
+    closed: bool,
+}
+
+/// Room taken from a [`Capacity`], to be given back once the request leaves the queues.
+pub(crate) struct Room {
+    priority: Priority,
+    /// Only set when tenants have a capacity of their own.
+    tenant: Tenant,
+}
+
+/// Why [`Capacity::take`] couldn't take room.
+#[derive(Debug, PartialEq)]
+pub(crate) enum NoRoom {
+    Full,
+    /// The background thread has stopped.
+    Closed,
+}
+
+impl Capacity {
+    pub(crate) fn new(config: &crate::ClientConfig) -> Self {
+        Self {
+            capacity: config.maximum_queue_size,
+            level_capacities: config.level_queue_sizes.clone(),
+            tenant_capacity: config.fairness.as_ref().and_then(|f| f.tenant_queue_size),
+            len: 0,
+            level_lens: BTreeMap::new(),
+            tenant_lens: HashMap::new(),
+            closed: false,
+        }
+    }
+
+    /// Take room for a request at `priority` from `tenant`, if the queues, its level and its tenant all have some.
+    pub(crate) fn take(&mut self, priority: Priority, tenant: Tenant) -> Result<Room, NoRoom> {
+        if self.closed {
+            return Err(NoRoom::Closed);
+        }
+        let tenant = tenant.filter(|_| self.tenant_capacity.is_some());
+        let level_len = self.level_lens.get(&priority).copied().unwrap_or(0);
+        let tenant_len = tenant.as_ref().and_then(|t| self.tenant_lens.get(t)).copied().unwrap_or(0);
+        if self.len >= self.capacity
+            || self.level_capacities.get(&priority).is_some_and(|c| level_len >= *c)
+            || self.tenant_capacity.is_some_and(|c| tenant.is_some() && tenant_len >= c)
+        {
+            return Err(NoRoom::Full);
+        }
+
+        self.len += 1;
+        *self.level_lens.entry(priority).or_default() += 1;
+        if let Some(t) = tenant.as_ref() {
+            *self.tenant_lens.entry(t.clone()).or_default() += 1;
+        }
+        Ok(Room { priority, tenant })
+    }
+
+    pub(crate) fn give_back(&mut self, room: &Room) {
+        self.len -= 1;
+        if let Some(len) = self.level_lens.get_mut(&room.priority) {
+            *len -= 1;
+            if *len == 0 {
+                self.level_lens.remove(&room.priority);
+            }
+        }
+        if let Some(t) = room.tenant.as_ref() {
+            if let Some(len) = self.tenant_lens.get_mut(t) {
+                *len -= 1;
+                if *len == 0 {
+                    self.tenant_lens.remove(t);
+                }
+            }
+        }
+    }
+
+    /// Refuse any more room, rooms still out can be given back.
+    pub(crate) fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+/// The tenants with something waiting at one priority level, the front one is next in line.
+type Level<T> = VecDeque<(Tenant, VecDeque<(Instant, T)>)>;
+
+/// Requests waiting to be started, one FIFO queue per priority level and tenant that has anything in it.
+pub(crate) struct PriorityQueues<T> {
+    /// Each item along with when it was queued, empty levels and tenants are removed.
+    levels: BTreeMap<Priority, Level<T>>,
+    len: usize,
+    capacity: usize,
+    level_capacities: BTreeMap<Priority, usize>,
+    scheduling: Scheduling,
+    /// The running credit of each busy level for [`Scheduling::WeightedRoundRobin`].
+    credits: BTreeMap<Priority, i64>,
+    /// Without fairness every request goes to the anonymous tenant.
+    fairness: Option<Fairness>,
+    tenant_lens: HashMap<String, usize>,
+    tenant_limiters: HashMap<String, RateLimiter>,
+}
+
+impl<T> PriorityQueues<T> {
+    /// `capacity` is shared by every level, `level_capacities` additionally limits the levels in it.
+    pub(crate) fn new(capacity: usize, level_capacities: BTreeMap<Priority, usize>, scheduling: Scheduling, fairness: Option<Fairness>) -> Self {
+        Self {
+            levels: BTreeMap::new(),
+            len: 0,
+            capacity,
+            level_capacities,
+            scheduling,
+            credits: BTreeMap::new(),
+            fairness,
+            tenant_lens: HashMap::new(),
+            tenant_limiters: HashMap::new(),
+        }
+    }
+
+    /// Queue an item, handing it back if the queue, its priority level or its tenant is already at capacity.
+    pub(crate) fn push(&mut self, priority: Priority, tenant: Tenant, item: T, now: Instant) -> Result<(), T> {
+        let tenant = self.fairness.as_ref().and(tenant);
+        let level_len = self.levels.get(&priority).map_or(0, |level| level.iter().map(|(_, queue)| queue.len()).sum());
+        let tenant_len = tenant.as_ref().and_then(|t| self.tenant_lens.get(t)).copied().unwrap_or(0);
+        let tenant_capacity = tenant.as_ref().and(self.fairness.as_ref()).and_then(|f| f.tenant_queue_size);
+        if self.len >= self.capacity
+            || self.level_capacities.get(&priority).is_some_and(|c| level_len >= *c)
+            || tenant_capacity.is_some_and(|c| tenant_len >= c)
+        {
+            return Err(item);
+        }
+
+        if let Some(t) = tenant.as_ref() {
+            *self.tenant_lens.entry(t.clone()).or_default() += 1;
+            // limiters of tenants that went quiet only matter until their bucket has refilled
+            if self.tenant_limiters.len() > 2 * self.tenant_lens.len() + 16 {
+                let tenant_lens = &self.tenant_lens;
+                self.tenant_limiters.retain(|t, limiter| tenant_lens.contains_key(t) || !limiter.delay(now).is_zero());
+            }
+        }
+        let level = self.levels.entry(priority).or_default();
+        match level.iter_mut().find(|(t, _)| *t == tenant) {
+            Some((_, queue)) => queue.push_back((now, item)),
+            None => level.push_back((tenant, VecDeque::from([(now, item)]))),
+        }
+        self.len += 1;
+        Ok(())
+    }
+
+    /// For every level, the position of the first tenant in line that may send right now and when its next item was queued.
+    fn ready(&mut self, now: Instant) -> Vec<(Priority, usize, Instant)> {
+        let limit = self.fairness.as_ref().and_then(|f| f.tenant_rate_limit.as_ref());
+        let limiters = &mut self.tenant_limiters;
+        self.levels
+            .iter()
+            .filter_map(|(priority, level)| {
+                level.iter().enumerate().find_map(|(i, (tenant, queue))| {
+                    let (queued_at, _) = queue.front()?;
+                    tenant_ready(limit, limiters, tenant, now).then_some((*priority, i, *queued_at))
+                })
+            })
+            .collect()
+    }
+
+    /// How long until [`PriorityQueues::pop`] can return something, zero if it can right now, `None` if the queues are empty.
+    pub(crate) fn delay(&mut self, now: Instant) -> Option<Duration> {
+        if self.is_empty() {
+            return None;
+        }
+        if !self.ready(now).is_empty() {
+            return Some(Duration::ZERO);
+        }
+        // everything waiting belongs to tenants that are over their rate
+        let tenant_lens = &self.tenant_lens;
+        self.tenant_limiters.iter_mut().filter(|(t, _)| tenant_lens.contains_key(*t)).map(|(_, limiter)| limiter.delay(now)).min()
+    }
+
+    /// Take the next item according to the [`Scheduling`] policy, rotating through the tenants of its level.
+    pub(crate) fn pop(&mut self, now: Instant) -> Option<(Priority, T)> {
+        let ready = self.ready(now);
+        let (priority, i, _) = match &self.scheduling {
+            Scheduling::Strict => ready.into_iter().next_back()?,
+            Scheduling::WeightedRoundRobin { .. } => {
+                let picked = self.next_weighted(ready.iter().map(|(priority, _, _)| *priority))?;
+                ready.into_iter().find(|(priority, _, _)| *priority == picked)?
+            }
+            // the next item of each level is the only one that can have aged the furthest, ties go to the oldest
+            Scheduling::Aging { step } => {
+                let aged = |queued_at: Instant, priority: Priority| {
+                    let steps = now.saturating_duration_since(queued_at).as_nanos() / step.as_nanos();
+                    (priority.0 as u128 + steps).min(u8::MAX as u128)
+                };
+                ready.into_iter().max_by_key(|(priority, _, queued_at)| (aged(*queued_at, *priority), std::cmp::Reverse(*queued_at)))?
+            }
+        };
+
+        let level = self.levels.get_mut(&priority)?;
+        let (tenant, mut queue) = level.remove(i)?;
+        let (_, item) = queue.pop_front()?;
+        if !queue.is_empty() {
+            level.push_back((tenant.clone(), queue));
+        }
+        if level.is_empty() {
+            self.levels.remove(&priority);
+            self.credits.remove(&priority);
+        }
+        if let Some(t) = tenant.as_ref() {
+            if let Some(limiter) = self.tenant_limiters.get_mut(t) {
+                limiter.try_acquire(now);
+            }
+        }
+        self.forget(&tenant, 1);
+        Some((priority, item))
+    }
+
+    /// Smooth weighted round robin: every busy level earns its weight, the richest one is picked and pays back the total.
+    fn next_weighted(&mut self, busy: impl Iterator<Item = Priority>) -> Option<Priority> {
+        let mut total = 0;
+        for priority in busy {
+            let weight = self.scheduling.weight(priority);
+            *self.credits.entry(priority).or_default() += weight;
+            total += weight;
+        }
+        // ties go to the higher priority, levels only hold credit while they are busy
+        let (picked, _) = self.credits.iter().max_by_key(|(priority, credit)| (**credit, **priority))?;
+        let picked = *picked;
+        *self.credits.get_mut(&picked)? -= total;
+        Some(picked)
+    }
+
+    /// Account for `n` items of `tenant` leaving the queues.
+    fn forget(&mut self, tenant: &Tenant, n: usize) {
+        self.len -= n;
+        if let Some(t) = tenant.as_ref() {
+            if let Some(len) = self.tenant_lens.get_mut(t) {
+                *len -= n;
+                if *len == 0 {
+                    self.tenant_lens.remove(t);
+                }
+            }
+        }
+    }
+
+    /// Take the first item matching `f` out of whichever queue it is in.
+    pub(crate) fn remove_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Option<T> {
+        let (priority, i, j) = self.levels.iter().find_map(|(priority, level)| {
+            level
+                .iter()
+                .enumerate()
+                .find_map(|(i, (_, queue))| queue.iter().position(|(_, item)| f(item)).map(|j| (*priority, i, j)))
+        })?;
+        let level = self.levels.get_mut(&priority)?;
+        let (tenant, queue) = level.get_mut(i)?;
+        let tenant = tenant.clone();
+        let (_, item) = queue.remove(j)?;
+        if queue.is_empty() {
+            level.remove(i);
+        }
+        if level.is_empty() {
+            self.levels.remove(&priority);
+            self.credits.remove(&priority);
+        }
+        self.forget(&tenant, 1);
+        Some(item)
+    }
+
+    /// Take every item matching `f` out of all the queues.
+    pub(crate) fn take_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<T> {
+        let mut taken = Vec::new();
+        let mut forgotten = Vec::new();
+        for level in self.levels.values_mut() {
+            for (tenant, queue) in level.iter_mut() {
+                let (matching, rest) = std::mem::take(queue).into_iter().partition::<Vec<_>, _>(|(_, item)| f(item));
+                *queue = rest.into();
+                forgotten.push((tenant.clone(), matching.len()));
+                taken.extend(matching.into_iter().map(|(_, item)| item));
+            }
+            level.retain(|(_, queue)| !queue.is_empty());
+        }
+        self.levels.retain(|_, level| !level.is_empty());
+        self.credits.retain(|priority, _| self.levels.contains_key(priority));
+        for (tenant, n) in forgotten.into_iter().filter(|(_, n)| *n > 0) {
+            self.forget(&tenant, n);
+        }
+        taken
+    }
+
+    /// Take every item, highest priority first.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = T> {
+        self.len = 0;
+        self.credits.clear();
+        self.tenant_lens.clear();
+        std::mem::take(&mut self.levels).into_values().rev().flatten().flat_map(|(_, queue)| queue).map(|(_, item)| item)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// Whether the tenant's own rate limit lets a request through right now.
+fn tenant_ready(limit: Option<&RateLimit>, limiters: &mut HashMap<String, RateLimiter>, tenant: &Tenant, now: Instant) -> bool {
+    let (Some(limit), Some(tenant)) = (limit, tenant) else {
+        return true;
+    };
+    // only a tenant's first request allocates its key
+    if !limiters.contains_key(tenant) {
+        limiters.insert(tenant.clone(), RateLimiter::new(limit, None, SharedRate::new(limit.requests_per_second), now));
+    }
+    limiters.get_mut(tenant).is_some_and(|limiter| limiter.delay(now).is_zero())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEVELS: [Priority; 3] = [Priority::HIGH, Priority::NORMAL, Priority::LOW];
+
+    fn filled(scheduling: Scheduling, now: Instant) -> PriorityQueues<u32> {
+        let mut queues = PriorityQueues::new(64, BTreeMap::new(), scheduling, None);
+        for i in 0..8 {
+            for priority in LEVELS {
+                queues.push(priority, None, i, now).unwrap();
+            }
+        }
+        queues
+    }
+
+    fn order(queues: &mut PriorityQueues<u32>, now: Instant, n: usize) -> Vec<Priority> {
+        (0..n).map(|_| queues.pop(now).unwrap().0).collect()
+    }
+
+    #[test]
+    fn test_strict_scheduling() {
+        let now = Instant::now();
+        let mut queues = filled(Scheduling::Strict, now);
+
+        assert_eq!(order(&mut queues, now, 9), [[Priority::HIGH; 8].as_slice(), &[Priority::NORMAL]].concat());
+    }
+
+    #[test]
+    fn test_arbitrary_levels() {
+        let now = Instant::now();
+        let mut queues = PriorityQueues::new(64, BTreeMap::new(), Scheduling::Strict, None);
+        for level in [3, 250, 0, 129] {
+            queues.push(Priority(level), None, level, now).unwrap();
+        }
+
+        assert_eq!((0..4).map(|_| queues.pop(now).unwrap().1).collect::<Vec<_>>(), [250, 129, 3, 0]);
+        assert!(queues.is_empty());
+    }
+
+    #[test]
+    fn test_capacities() {
+        let now = Instant::now();
+        let mut queues = PriorityQueues::new(3, BTreeMap::from([(Priority::LOW, 1)]), Scheduling::Strict, None);
+
+        assert!(queues.push(Priority::LOW, None, 0, now).is_ok());
+        assert!(queues.push(Priority::LOW, None, 1, now).is_err());
+        assert!(queues.push(Priority::HIGH, None, 2, now).is_ok());
+        assert!(queues.push(Priority::HIGH, None, 3, now).is_ok());
+        assert!(queues.push(Priority::HIGH, None, 4, now).is_err());
+        assert_eq!(queues.drain().count(), 3);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let fairness = FairnessBuilder::default().tenant_queue_size(1).build().unwrap();
+        let config = crate::ClientConfigBuilder::default()
+            .api_key("key".into())
+            .maximum_queue_size(3)
+            .level_queue_size(Priority::LOW, 1)
+            .fairness(fairness)
+            .build()
+            .unwrap();
+        let mut capacity = Capacity::new(&config);
+
+        let low = capacity.take(Priority::LOW, None).unwrap();
+        assert_eq!(capacity.take(Priority::LOW, None).err(), Some(NoRoom::Full));
+        let tenant = capacity.take(Priority::HIGH, Some("noisy".into())).unwrap();
+        assert_eq!(capacity.take(Priority::HIGH, Some("noisy".into())).err(), Some(NoRoom::Full));
+        capacity.take(Priority::HIGH, Some("quiet".into())).unwrap();
+        assert_eq!(capacity.take(Priority::HIGH, None).err(), Some(NoRoom::Full));
+
+        capacity.give_back(&low);
+        capacity.give_back(&tenant);
+        assert!(capacity.take(Priority::LOW, Some("noisy".into())).is_ok());
+        capacity.close();
+        assert_eq!(capacity.take(Priority::HIGH, None).err(), Some(NoRoom::Closed));
+    }
+
+    #[test]
+    fn test_weighted_round_robin_scheduling() {
+        let now = Instant::now();
+        let weights = BTreeMap::from([(Priority::HIGH, 4), (Priority::NORMAL, 2)]);
+        let mut queues = filled(Scheduling::WeightedRoundRobin { weights }, now);
+
+        let order = order(&mut queues, now, 7);
+
+        assert_eq!(order.iter().filter(|p| **p == Priority::HIGH).count(), 4);
+        assert_eq!(order.iter().filter(|p| **p == Priority::NORMAL).count(), 2);
+        assert_eq!(order.iter().filter(|p| **p == Priority::LOW).count(), 1);
+    }
+
+    #[test]
+    fn test_aging_scheduling() {
+        let step = Duration::from_secs(10);
+        let start = Instant::now();
+        let mut queues = PriorityQueues::new(64, BTreeMap::new(), Scheduling::Aging { step }, None);
+        queues.push(Priority(1), None, 0, start).unwrap();
+
+        // a steady stream of fresh requests two levels up holds the old one back for two steps
+        for (i, elapsed) in [0, 1, 2].into_iter().enumerate() {
+            let now = start + step * elapsed;
+            queues.push(Priority(3), None, i as u32 + 1, now).unwrap();
+            let expected = if elapsed < 2 { Priority(3) } else { Priority(1) };
+            assert_eq!(queues.pop(now).unwrap().0, expected);
+        }
+    }
+
+    #[test]
+    fn test_fair_queuing_between_tenants() {
+        let now = Instant::now();
+        let fairness = FairnessBuilder::default().tenant_queue_size(3).build().unwrap();
+        let mut queues = PriorityQueues::new(64, BTreeMap::new(), Scheduling::Strict, Some(fairness));
+        for i in 0..4 {
+            let pushed = queues.push(Priority::NORMAL, Some("noisy".into()), i, now);
+            assert_eq!(pushed.is_ok(), i < 3);
+        }
+        queues.push(Priority::NORMAL, Some("quiet".into()), 10, now).unwrap();
+        queues.push(Priority::NORMAL, None, 20, now).unwrap();
+
+        assert_eq!((0..5).map(|_| queues.pop(now).unwrap().1).collect::<Vec<_>>(), [0, 10, 20, 1, 2]);
+    }
+
+    #[test]
+    fn test_tenant_rate_limit() {
+        let now = Instant::now();
+        let rate_limit = crate::RateLimitBuilder::default().requests_per_second(1.0).build().unwrap();
+        let fairness = FairnessBuilder::default().tenant_rate_limit(rate_limit).build().unwrap();
+        let mut queues = PriorityQueues::new(64, BTreeMap::new(), Scheduling::Strict, Some(fairness));
+        queues.push(Priority::HIGH, Some("noisy".into()), 0, now).unwrap();
+        queues.push(Priority::HIGH, Some("noisy".into()), 1, now).unwrap();
+        queues.push(Priority::LOW, Some("quiet".into()), 2, now).unwrap();
+
+        assert_eq!(queues.pop(now).unwrap().1, 0);
+        // the noisy tenant is over its rate, so the lower priority request of another tenant goes first
+        assert_eq!(queues.pop(now).unwrap().1, 2);
+        assert_eq!(queues.delay(now), Some(Duration::from_secs(1)));
+        assert!(queues.pop(now).is_none());
+        assert_eq!(queues.pop(now + Duration::from_secs(1)).unwrap().1, 1);
+        assert_eq!(queues.delay(now), None);
+    }
+
+    #[test]
+    fn test_scheduling_validate() {
+        assert!(Scheduling::WeightedRoundRobin { weights: BTreeMap::from([(Priority::LOW, 0)]) }.validate().is_err());
+        assert!(Scheduling::Aging { step: Duration::ZERO }.validate().is_err());
+        assert!(Scheduling::Strict.validate().is_ok());
+    }
+}
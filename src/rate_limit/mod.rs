@@ -0,0 +1,371 @@
+use std::time::{Duration, Instant};
+
+/// How fast the client is allowed to send requests to the API.
+///
+/// Requests are let through by a token bucket that refills at `requests_per_second` and holds at most `burst` tokens,
+/// on top of optional hard ceilings per minute and per day.
+#[derive(derive_builder::Builder, Clone, Debug, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct RateLimit {
+    /// The sustained number of requests per second. Perspective grants 1 QPS by default.
+    #[builder(default = "1.0")]
+    pub requests_per_second: f64,
+    /// How many requests can be sent back to back after the client has been idle.
+    #[builder(default = "1")]
+    pub burst: u32,
+    /// The maximum number of requests started in any one minute.
+    #[builder(default, setter(strip_option))]
+    pub requests_per_minute: Option<u32>,
+    /// The maximum number of requests started in any one day.
+    #[builder(default, setter(strip_option))]
+    pub requests_per_day: Option<u32>,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            requests_per_second: 1.0,
+            burst: 1,
+            requests_per_minute: None,
+            requests_per_day: None,
+        }
+    }
+}
+
+impl RateLimit {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !self.requests_per_second.is_finite() || self.requests_per_second <= 0.0 {
+            return Err("requests per second must be a positive number".into());
+        }
+
+        if self.burst == 0 {
+            return Err("burst cannot be 0".into());
+        }
+
+        if let Some(0) = self.requests_per_minute {
+            return Err("requests per minute cannot be 0".into());
+        }
+
+        if let Some(0) = self.requests_per_day {
+            return Err("requests per day cannot be 0".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl RateLimitBuilder {
+    fn validate(&self) -> Result<(), String> {
+        RateLimit {
+            requests_per_second: self.requests_per_second.unwrap_or(1.0),
+            burst: self.burst.unwrap_or(1),
+            requests_per_minute: self.requests_per_minute.flatten(),
+            requests_per_day: self.requests_per_day.flatten(),
+        }
+        .validate()
+    }
+}
+
+/// How the client backs off when the API reports that the quota has been exceeded, and how it recovers afterwards.
+///
+/// Every quota error multiplies the sending rate by `decrease_factor` (down to `minimum_requests_per_second`) and
+/// honours any `Retry-After` the API sent, every successful request then adds `increase_per_success` back until the
+/// configured [`RateLimit::requests_per_second`] is reached again.
+#[derive(derive_builder::Builder, Clone, Debug, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct AdaptiveRate {
+    /// What the rate is multiplied by after a quota error, between 0 and 1.
+    #[builder(default = "0.5")]
+    pub decrease_factor: f64,
+    /// How many requests per second are added back after every successful request.
+    #[builder(default = "0.05")]
+    pub increase_per_success: f64,
+    /// The rate will never be lowered below this.
+    #[builder(default = "0.05")]
+    pub minimum_requests_per_second: f64,
+}
+
+impl Default for AdaptiveRate {
+    fn default() -> Self {
+        Self {
+            decrease_factor: 0.5,
+            increase_per_success: 0.05,
+            minimum_requests_per_second: 0.05,
+        }
+    }
+}
+
+impl AdaptiveRate {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !(self.decrease_factor > 0.0 && self.decrease_factor < 1.0) {
+            return Err("decrease factor must be between 0 and 1".into());
+        }
+
+        if !self.increase_per_success.is_finite() || self.increase_per_success < 0.0 {
+            return Err("increase per success cannot be negative".into());
+        }
+
+        if !self.minimum_requests_per_second.is_finite() || self.minimum_requests_per_second <= 0.0 {
+            return Err("minimum requests per second must be a positive number".into());
+        }
+
+        Ok(())
+    }
+}
+
+impl AdaptiveRateBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let defaults = AdaptiveRate::default();
+        AdaptiveRate {
+            decrease_factor: self.decrease_factor.unwrap_or(defaults.decrease_factor),
+            increase_per_success: self.increase_per_success.unwrap_or(defaults.increase_per_success),
+            minimum_requests_per_second: self.minimum_requests_per_second.unwrap_or(defaults.minimum_requests_per_second),
+        }
+        .validate()
+    }
+}
+
+/// The rate the background thread is currently sending at, readable from the client.
+#[derive(Clone, Debug)]
+pub(crate) struct SharedRate(std::sync::Arc<std::sync::atomic::AtomicU64>);
+
+impl SharedRate {
+    pub(crate) fn new(requests_per_second: f64) -> Self {
+        Self(std::sync::Arc::new(std::sync::atomic::AtomicU64::new(requests_per_second.to_bits())))
+    }
+
+    pub(crate) fn get(&self) -> f64 {
+        f64::from_bits(self.0.load(std::sync::atomic::Ordering::Relaxed))
+    }
+
+    fn set(&self, requests_per_second: f64) {
+        self.0.store(requests_per_second.to_bits(), std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+/// A fixed window that allows at most `limit` requests every `length`.
+#[derive(Debug)]
+struct Window {
+    length: Duration,
+    limit: u32,
+    started: Option<Instant>,
+    count: u32,
+}
+
+impl Window {
+    fn new(length: Duration, limit: u32) -> Self {
+        Self {
+            length,
+            limit,
+            started: None,
+            count: 0,
+        }
+    }
+
+    fn roll(&mut self, now: Instant) {
+        if let Some(started) = self.started {
+            if now.saturating_duration_since(started) >= self.length {
+                self.started = None;
+                self.count = 0;
+            }
+        }
+    }
+
+    fn delay(&self, now: Instant) -> Duration {
+        match self.started {
+            Some(started) if self.count >= self.limit => (started + self.length).saturating_duration_since(now),
+            _ => Duration::ZERO,
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        self.started.get_or_insert(now);
+        self.count += 1;
+    }
+}
+
+/// Decides when the next request may start, according to a [`RateLimit`] and optionally an [`AdaptiveRate`].
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    /// The configured rate, the adaptive rate never goes above this.
+    ceiling: f64,
+    requests_per_second: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    windows: Vec<Window>,
+    adaptive: Option<AdaptiveRate>,
+    /// Nothing is let through before this, set from `Retry-After`.
+    paused_until: Option<Instant>,
+    last_decrease: Option<Instant>,
+    shared: SharedRate,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: &RateLimit, adaptive: Option<AdaptiveRate>, shared: SharedRate, now: Instant) -> Self {
+        let mut windows = Vec::new();
+        if let Some(limit) = limit.requests_per_minute {
+            windows.push(Window::new(Duration::from_secs(60), limit));
+        }
+        if let Some(limit) = limit.requests_per_day {
+            windows.push(Window::new(Duration::from_secs(24 * 60 * 60), limit));
+        }
+
+        shared.set(limit.requests_per_second);
+
+        Self {
+            ceiling: limit.requests_per_second,
+            requests_per_second: limit.requests_per_second,
+            burst: limit.burst as f64,
+            tokens: limit.burst as f64,
+            refilled: now,
+            windows,
+            adaptive,
+            paused_until: None,
+            last_decrease: None,
+            shared,
+        }
+    }
+
+    fn set_rate(&mut self, requests_per_second: f64) {
+        self.requests_per_second = requests_per_second;
+        self.shared.set(requests_per_second);
+    }
+
+    /// Slow down after the API reported that the quota has been exceeded.
+    pub(crate) fn on_quota_exceeded(&mut self, now: Instant, retry_after: Option<Duration>) {
+        if let Some(retry_after) = retry_after {
+            let until = now + retry_after;
+            self.paused_until = Some(self.paused_until.map_or(until, |p| p.max(until)));
+        }
+
+        let Some(adaptive) = self.adaptive.as_ref() else {
+            return;
+        };
+
+        // every request that was in flight when the quota ran out will come back with the same error, only count them once
+        let cooldown = Duration::from_secs_f64(1.0 / self.requests_per_second);
+        if self.last_decrease.is_some_and(|last| now.saturating_duration_since(last) < cooldown) {
+            return;
+        }
+        self.last_decrease = Some(now);
+
+        let rate = (self.requests_per_second * adaptive.decrease_factor).max(adaptive.minimum_requests_per_second.min(self.ceiling));
+        log::info!("quota exceeded, lowering rate to {} requests per second", rate);
+        self.refill(now);
+        self.tokens = self.tokens.min(0.0);
+        self.set_rate(rate);
+    }
+
+    /// Recover some of the rate after a request went through.
+    pub(crate) fn on_success(&mut self) {
+        let Some(adaptive) = self.adaptive.as_ref() else {
+            return;
+        };
+
+        if self.requests_per_second < self.ceiling {
+            let rate = (self.requests_per_second + adaptive.increase_per_success).min(self.ceiling);
+            self.set_rate(rate);
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.requests_per_second).min(self.burst);
+        self.refilled = now;
+        for window in self.windows.iter_mut() {
+            window.roll(now);
+        }
+    }
+
+    /// How long to wait before a request may start.
+    pub(crate) fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        let bucket = if self.tokens >= 1.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((1.0 - self.tokens) / self.requests_per_second)
+        };
+        let paused = self.paused_until.map_or(Duration::ZERO, |p| p.saturating_duration_since(now));
+        self.windows.iter().map(|w| w.delay(now)).fold(bucket.max(paused), Duration::max)
+    }
+
+    /// Take a permit for a request if one is available right now.
+    pub(crate) fn try_acquire(&mut self, now: Instant) -> bool {
+        if !self.delay(now).is_zero() {
+            return false;
+        }
+        self.tokens -= 1.0;
+        for window in self.windows.iter_mut() {
+            window.record(now);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter_burst_then_refill() {
+        let start = Instant::now();
+        let limit = RateLimitBuilder::default().requests_per_second(2.0).burst(3).build().unwrap();
+        let mut limiter = RateLimiter::new(&limit, None, SharedRate::new(0.0), start);
+
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start));
+        assert_eq!(limiter.delay(start), Duration::from_millis(500));
+
+        assert!(limiter.try_acquire(start + Duration::from_millis(500)));
+        assert!(!limiter.try_acquire(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_rate_limiter_per_minute_ceiling() {
+        let start = Instant::now();
+        let limit = RateLimitBuilder::default().requests_per_second(10.0).burst(10).requests_per_minute(2).build().unwrap();
+        let mut limiter = RateLimiter::new(&limit, None, SharedRate::new(0.0), start);
+
+        assert!(limiter.try_acquire(start));
+        assert!(limiter.try_acquire(start));
+        assert!(!limiter.try_acquire(start + Duration::from_secs(1)));
+        assert_eq!(limiter.delay(start + Duration::from_secs(1)), Duration::from_secs(59));
+        assert!(limiter.try_acquire(start + Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_rate_limiter_adaptive_backoff_and_recovery() {
+        let start = Instant::now();
+        let limit = RateLimitBuilder::default().requests_per_second(4.0).burst(4).build().unwrap();
+        let adaptive = AdaptiveRateBuilder::default().decrease_factor(0.5).increase_per_success(1.0).build().unwrap();
+        let shared = SharedRate::new(0.0);
+        let mut limiter = RateLimiter::new(&limit, Some(adaptive), shared.clone(), start);
+        assert_eq!(shared.get(), 4.0);
+
+        limiter.on_quota_exceeded(start, Some(Duration::from_secs(2)));
+        // the rest of the in flight requests failing shouldn't lower the rate again
+        limiter.on_quota_exceeded(start, None);
+        assert_eq!(shared.get(), 2.0);
+        assert!(!limiter.try_acquire(start + Duration::from_secs(1)));
+        assert!(limiter.try_acquire(start + Duration::from_secs(2)));
+
+        limiter.on_success();
+        assert_eq!(shared.get(), 3.0);
+        limiter.on_success();
+        limiter.on_success();
+        assert_eq!(shared.get(), 4.0);
+    }
+
+    #[test]
+    fn test_rate_limit_builder_validate() {
+        assert!(RateLimitBuilder::default().requests_per_second(0.0).build().is_err());
+        assert!(RateLimitBuilder::default().burst(0).build().is_err());
+        assert!(RateLimitBuilder::default().requests_per_day(0).build().is_err());
+        assert!(RateLimitBuilder::default().build().is_ok());
+        assert!(AdaptiveRateBuilder::default().decrease_factor(1.0).build().is_err());
+        assert!(AdaptiveRateBuilder::default().build().is_ok());
+    }
+}
@@ -0,0 +1,239 @@
+use std::time::Duration;
+
+use crate::types::ApiError;
+
+/// When and how often failed requests are tried again.
+///
+/// Retried requests go back into the queue at their original priority, so every attempt counts against the rate limit.
+/// The delay before attempt `n + 1` is `base_delay * 2^(n - 1)`, capped at `max_delay`, with up to `jitter` of it
+/// randomly taken off so that a burst of failures doesn't come back as a burst of retries.
+#[derive(derive_builder::Builder, Clone, Debug, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct RetryPolicy {
+    /// The total number of attempts per request, including the first one. 1 disables retries.
+    #[builder(default = "3")]
+    pub max_attempts: u32,
+    #[builder(default = "Duration::from_millis(500)")]
+    pub base_delay: Duration,
+    #[builder(default = "Duration::from_secs(30)")]
+    pub max_delay: Duration,
+    /// The fraction of the delay that may be randomly removed, between 0 and 1.
+    #[builder(default = "0.5")]
+    pub jitter: f64,
+    /// Retry connection failures, timeouts and other errors from reqwest.
+    #[builder(default = "true")]
+    pub retry_transport_errors: bool,
+    /// Retry 5xx responses and `UNAVAILABLE`/`INTERNAL`/`DEADLINE_EXCEEDED` statuses.
+    #[builder(default = "true")]
+    pub retry_server_errors: bool,
+    /// Retry HTTP 429/`RESOURCE_EXHAUSTED`, waiting at least as long as the API's `Retry-After`.
+    #[builder(default = "true")]
+    pub retry_quota_exceeded: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            jitter: 0.5,
+            retry_transport_errors: true,
+            retry_server_errors: true,
+            retry_quota_exceeded: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.max_attempts == 0 {
+            return Err("max attempts cannot be 0".into());
+        }
+
+        if self.base_delay > self.max_delay {
+            return Err("base delay cannot be greater than max delay".into());
+        }
+
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err("jitter must be between 0 and 1".into());
+        }
+
+        Ok(())
+    }
+
+    /// Whether a request that failed with `error` on attempt number `attempts` should be tried again.
+    pub(crate) fn should_retry(&self, error: &ApiError, attempts: u32) -> bool {
+        if attempts >= self.max_attempts {
+            return false;
+        }
+
+        match error {
+            ApiError::Reqwest(_) => self.retry_transport_errors && error.is_retryable(),
+            ApiError::QuotaExceeded { .. } => self.retry_quota_exceeded,
+            _ => self.retry_server_errors && error.is_retryable(),
+        }
+    }
+
+    /// How long to wait before trying again after attempt number `attempts` failed.
+    pub(crate) fn delay(&self, attempts: u32, retry_after: Option<Duration>) -> Duration {
+        let exponential = self.base_delay.saturating_mul(2u32.saturating_pow(attempts.saturating_sub(1))).min(self.max_delay);
+        let jittered = exponential.mul_f64(1.0 - self.jitter * random_fraction());
+        jittered.max(retry_after.unwrap_or(Duration::ZERO))
+    }
+}
+
+impl RetryPolicyBuilder {
+    fn validate(&self) -> Result<(), String> {
+        let defaults = RetryPolicy::default();
+        RetryPolicy {
+            max_attempts: self.max_attempts.unwrap_or(defaults.max_attempts),
+            base_delay: self.base_delay.unwrap_or(defaults.base_delay),
+            max_delay: self.max_delay.unwrap_or(defaults.max_delay),
+            jitter: self.jitter.unwrap_or(defaults.jitter),
+            ..defaults
+        }
+        .validate()
+    }
+}
+
+/// What to do with a request whose attempt just finished.
+pub(crate) enum Settled {
+    /// Put the request back in its queue after `delay`, `error` is what to report if that isn't possible.
+    Retry { delay: Duration, error: ApiError },
+    /// Hand the result to the caller.
+    Done(crate::types::Response),
+}
+
+/// Feed the result of an attempt to the rate limiter and decide whether it should be retried.
+pub(crate) fn settle(res: crate::types::Response, attempts: u32, policy: &RetryPolicy, rate_limiter: &mut crate::rate_limit::RateLimiter) -> Settled {
+    match &res {
+        Ok(_) => rate_limiter.on_success(),
+        Err(ApiError::QuotaExceeded { retry_after, .. }) => rate_limiter.on_quota_exceeded(std::time::Instant::now(), *retry_after),
+        Err(_) => {}
+    }
+
+    match res {
+        Err(error) if policy.should_retry(&error, attempts) => {
+            let retry_after = match &error {
+                ApiError::QuotaExceeded { retry_after, .. } => *retry_after,
+                _ => None,
+            };
+            let delay = policy.delay(attempts, retry_after);
+            log::info!("retrying request in {:?} after attempt {} failed: {}", delay, attempts, error);
+            Settled::Retry { delay, error }
+        }
+        res => Settled::Done(with_attempts(res, attempts)),
+    }
+}
+
+/// Requests waiting out their retry delay before they go back into the queue.
+pub(crate) struct Backoffs<T> {
+    waiting: Vec<(std::time::Instant, ApiError, T)>,
+}
+
+impl<T> Backoffs<T> {
+    pub(crate) fn new() -> Self {
+        Self { waiting: Vec::new() }
+    }
+
+    pub(crate) fn push(&mut self, delay: Duration, error: ApiError, item: T) {
+        self.waiting.push((std::time::Instant::now() + delay, error, item));
+    }
+
+    /// When the next request is due, if there are any.
+    pub(crate) fn next_due(&self) -> Option<std::time::Instant> {
+        self.waiting.iter().map(|(at, _, _)| *at).min()
+    }
+
+    /// Take every request whose delay is over, along with the error it last failed with.
+    pub(crate) fn take_due(&mut self, now: std::time::Instant) -> Vec<(ApiError, T)> {
+        let (due, waiting) = std::mem::take(&mut self.waiting).into_iter().partition::<Vec<_>, _>(|(at, _, _)| *at <= now);
+        self.waiting = waiting;
+        due.into_iter().map(|(_, e, item)| (e, item)).collect()
+    }
+
+    /// Take the first request matching `f`, regardless of its delay.
+    pub(crate) fn remove_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Option<T> {
+        let i = self.waiting.iter().position(|(_, _, item)| f(item))?;
+        Some(self.waiting.swap_remove(i).2)
+    }
+
+    /// Take every request matching `f`, regardless of its delay.
+    pub(crate) fn take_where(&mut self, mut f: impl FnMut(&T) -> bool) -> Vec<T> {
+        let (matching, waiting) = std::mem::take(&mut self.waiting).into_iter().partition::<Vec<_>, _>(|(_, _, item)| f(item));
+        self.waiting = waiting;
+        matching.into_iter().map(|(_, _, item)| item).collect()
+    }
+
+    /// Take every request regardless of its delay.
+    pub(crate) fn drain(&mut self) -> impl Iterator<Item = T> + '_ {
+        self.waiting.drain(..).map(|(_, _, item)| item)
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+/// Record how many attempts it took to get to the final result of a request.
+pub(crate) fn with_attempts(res: crate::types::Response, attempts: u32) -> crate::types::Response {
+    match res {
+        Ok(mut res) => {
+            res.attempts = attempts;
+            Ok(res)
+        }
+        Err(e) if attempts > 1 => Err(ApiError::FailedAfterRetries { attempts, source: Box::new(e) }),
+        Err(e) => Err(e),
+    }
+}
+
+/// A number in `[0, 1)`, good enough for spreading out retries without pulling in a rng crate.
+fn random_fraction() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u128(std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retry_policy_delay() {
+        let policy = RetryPolicyBuilder::default()
+            .base_delay(Duration::from_millis(100))
+            .max_delay(Duration::from_millis(350))
+            .jitter(0.0)
+            .build()
+            .unwrap();
+
+        assert_eq!(policy.delay(1, None), Duration::from_millis(100));
+        assert_eq!(policy.delay(2, None), Duration::from_millis(200));
+        assert_eq!(policy.delay(3, None), Duration::from_millis(350));
+        assert_eq!(policy.delay(1, Some(Duration::from_secs(2))), Duration::from_secs(2));
+
+        let jittered = RetryPolicy { jitter: 1.0, ..policy }.delay(2, None);
+        assert!(jittered <= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_retry_policy_should_retry() {
+        let policy = RetryPolicyBuilder::default().max_attempts(2).retry_quota_exceeded(false).build().unwrap();
+
+        assert!(policy.should_retry(&ApiError::Status(503, String::new()), 1));
+        assert!(!policy.should_retry(&ApiError::Status(503, String::new()), 2));
+        assert!(!policy.should_retry(&ApiError::Status(404, String::new()), 1));
+        assert!(!policy.should_retry(&ApiError::QuotaExceeded { retry_after: None, error: None }, 1));
+        assert!(!RetryPolicy::never().should_retry(&ApiError::Status(503, String::new()), 1));
+    }
+}
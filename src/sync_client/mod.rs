@@ -0,0 +1,920 @@
+// the send errors hand the request back to the caller, same as the async client
+#![allow(clippy::result_large_err)]
+
+mod transport;
+pub use transport::*;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, SendError, SyncSender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::ClientConfig;
+
+/// A blocking perspective API client, which automatically handles rate limiting and requests on a background thread.
+///
+/// The client owns the shared response channel and decides when to shut down, requests can be sent through it
+/// directly or through any number of [`ClientHandle`]s.
+pub struct Client {
+    handle: ClientHandle,
+    receiver: Option<Receiver<crate::types::Response>>,
+}
+
+/// A cheap, cloneable way to send requests through a [`Client`] from many threads at once.
+///
+/// Every clone shares the client's queues and rate limit. The background thread stops once the client and every
+/// handle have been dropped.
+#[derive(Clone)]
+pub struct ClientHandle {
+    sender: SyncSender<Event>,
+    alive: Arc<Alive>,
+    /// Set by [`Client::shutdown`], no more requests are accepted after that.
+    closed: Arc<AtomicBool>,
+    rate: crate::rate_limit::SharedRate,
+    next_id: Arc<AtomicU64>,
+    /// Room for the requests that can be waiting in the queues, see [`ClientHandle::reserve`].
+    slots: Arc<Slots>,
+    cache_stats: crate::cache::SharedStats,
+    journal: Option<Arc<crate::journal::JournalFile>>,
+}
+
+/// Shared by the client and its handles, stops the background thread when the last of them is dropped.
+struct Alive(Feed);
+
+impl Drop for Alive {
+    fn drop(&mut self) {
+        // the thread stops as soon as it hears of this, requests in flight are left to finish on their own
+        self.0.close();
+    }
+}
+
+/// How the threads working for the client get into the background thread's channel, closed once the client and
+/// every handle have been dropped.
+///
+/// The background thread doesn't keep a sender of its own, so once this is closed and the last request in flight has
+/// reported back the channel disconnects and wakes it up.
+#[derive(Clone)]
+struct Feed(Arc<Mutex<Option<SyncSender<Event>>>>);
+
+impl Feed {
+    fn sender(&self) -> Option<SyncSender<Event>> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn close(&self) {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+
+    fn is_closed(&self) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).is_none()
+    }
+}
+
+/// Room in the queues shared by the client, its handles and the background thread, see [`crate::queue::Capacity`].
+struct Slots {
+    capacity: Mutex<crate::queue::Capacity>,
+    freed: Condvar,
+}
+
+/// Room for one request in the queues, given back when dropped.
+struct Slot {
+    slots: Arc<Slots>,
+    room: crate::queue::Room,
+}
+
+impl Slots {
+    /// Take room at `priority` for `tenant`, waiting until `deadline` for some to be freed, or forever without one.
+    fn acquire(self: &Arc<Self>, priority: crate::types::Priority, tenant: Option<String>, deadline: Option<Instant>) -> Result<Slot, TrySendError<()>> {
+        let mut capacity = self.capacity.lock().unwrap_or_else(|e| e.into_inner());
+        loop {
+            match capacity.take(priority, tenant.clone()) {
+                Ok(room) => return Ok(Slot { slots: self.clone(), room }),
+                Err(crate::queue::NoRoom::Closed) => return Err(TrySendError::Disconnected(())),
+                Err(crate::queue::NoRoom::Full) => {}
+            }
+            capacity = match deadline {
+                None => self.freed.wait(capacity).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    if timeout.is_zero() {
+                        return Err(TrySendError::Full(()));
+                    }
+                    self.freed.wait_timeout(capacity, timeout).unwrap_or_else(|e| e.into_inner()).0
+                }
+            };
+        }
+    }
+
+    fn close(&self) {
+        self.capacity.lock().unwrap_or_else(|e| e.into_inner()).close();
+        self.freed.notify_all();
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.slots.capacity.lock().unwrap_or_else(|e| e.into_inner()).give_back(&self.room);
+        // the room may only fit some of the waiters, so every one of them has to look
+        self.slots.freed.notify_all();
+    }
+}
+
+/// Room for one request in the queues, at the priority and for the tenant it was reserved with, see
+/// [`ClientHandle::reserve`].
+///
+/// Dropping the permit gives the room back.
+pub struct Permit<'a> {
+    client: &'a ClientHandle,
+    options: crate::types::RequestOptions,
+    slot: Slot,
+}
+
+impl Permit<'_> {
+    /// Like [`ClientHandle::analyze`], without waiting for room.
+    pub fn analyze(self, req: crate::types::Request) -> Result<ResponseTicket, SendError<crate::types::RequestWithPriority>> {
+        let (responder, receiver) = std::sync::mpsc::sync_channel(1);
+        let id = self.enqueue(req, Some(responder), true).map_err(|(TrySendError::Full(r) | TrySendError::Disconnected(r))| SendError(r))?;
+        Ok(ResponseTicket { id, receiver })
+    }
+    /// Like [`ClientHandle::send`], without waiting for room.
+    pub fn send(self, req: crate::types::Request) -> Result<crate::types::RequestId, SendError<crate::types::RequestWithPriority>> {
+        self.enqueue(req, None, true).map_err(|(TrySendError::Full(r) | TrySendError::Disconnected(r))| SendError(r))
+    }
+    /// Hand the request to the background thread, `block` waits for the channel to it if that is full.
+    fn enqueue(self, req: crate::types::Request, responder: Option<SyncSender<crate::types::Response>>, block: bool) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        let id = crate::types::RequestId(self.client.next_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(journal) = self.client.journal.as_ref() {
+            futures::executor::block_on(journal.accept(crate::journal::Record::new(id, &req, &self.options)));
+        }
+        let event = Event::Request(Queued::new(id, req, self.options, responder, self.slot));
+        let sent = match block {
+            true => self.client.sender.send(event).map_err(|e| TrySendError::Disconnected(e.0)),
+            false => self.client.sender.try_send(event),
+        };
+        if let (Some(journal), Err(_)) = (self.client.journal.as_ref(), &sent) {
+            // handed back to the caller, so it is theirs to send again
+            journal.answer(id);
+        }
+        let (e, event) = match sent {
+            Ok(()) => return Ok(id),
+            Err(TrySendError::Full(event)) => (TrySendError::Full(()), event),
+            Err(TrySendError::Disconnected(event)) => (TrySendError::Disconnected(()), event),
+        };
+        match unsent(event) {
+            Some(request) => Err(with_request(e, request)),
+            None => {
+                // its ticket resolves to ClientClosed, as the request was dropped
+                log::error!("failed to hand request {} back: something other than a request came back from the channel", id);
+                Ok(id)
+            }
+        }
+    }
+}
+
+/// Put the request back into an error from reserving room for it.
+fn with_request(e: TrySendError<()>, request: crate::types::RequestWithPriority) -> TrySendError<crate::types::RequestWithPriority> {
+    match e {
+        TrySendError::Full(()) => TrySendError::Full(request),
+        TrySendError::Disconnected(()) => TrySendError::Disconnected(request),
+    }
+}
+
+/// The request of an event that couldn't be sent to the background thread, `None` if it isn't a request.
+fn unsent(event: Event) -> Option<crate::types::RequestWithPriority> {
+    match event {
+        Event::Request(queued) => Some(queued.request),
+        _ => None,
+    }
+}
+
+type Queued = crate::dispatch::Queued<Outbox>;
+
+/// Where the background thread takes room in the queues and delivers responses, see [`crate::dispatch::Outlet`].
+struct Outbox {
+    sender: std::sync::mpsc::Sender<(crate::types::Response, Option<Slot>)>,
+    slots: Arc<Slots>,
+}
+
+impl crate::dispatch::Outlet for Outbox {
+    type Slot = Slot;
+    type Responder = SyncSender<crate::types::Response>;
+
+    fn try_acquire(&self, priority: crate::types::Priority, tenant: Option<String>) -> Option<Slot> {
+        self.slots.acquire(priority, tenant, Some(Instant::now())).ok()
+    }
+
+    /// Deliver a response to its ticket if it has one, otherwise hand it to [`forward`] for the shared response channel.
+    fn deliver(&self, responder: Option<Self::Responder>, slot: Option<Slot>, res: crate::types::Response) {
+        match responder {
+            Some(responder) => {
+                if responder.send(res).is_err() {
+                    log::info!("response ticket was dropped before the response arrived");
+                }
+            }
+            None => {
+                if self.sender.send((res, slot)).is_err() {
+                    log::error!("failed to send response: the response channel is closed");
+                }
+            }
+        }
+    }
+}
+
+/// Everything the background thread waits on comes through one channel.
+enum Event {
+    Request(Queued),
+    Done(crate::types::Response, Queued),
+    /// Cancel a request if it is still waiting, answering whether it was.
+    Cancel(crate::types::RequestId, SyncSender<bool>),
+    /// Sent by [`Client::shutdown`] along with where to send the requests that were never sent.
+    Shutdown(crate::types::ShutdownMode, SyncSender<Vec<crate::types::RequestWithPriority>>),
+}
+
+/// A handle to the response of a single request sent with [`ClientHandle::analyze`].
+pub struct ResponseTicket {
+    id: crate::types::RequestId,
+    receiver: Receiver<crate::types::Response>,
+}
+
+impl ResponseTicket {
+    /// The id to pass to [`ClientHandle::cancel`].
+    pub fn id(&self) -> crate::types::RequestId {
+        self.id
+    }
+    /// Block until the response for this request arrives.
+    pub fn wait(self) -> crate::types::Response {
+        self.receiver.recv().unwrap_or(Err(crate::types::ApiError::ClientClosed))
+    }
+}
+
+impl Client {
+    /// Create a client sending requests with reqwest, configured from [`ClientConfig::http`].
+    ///
+    /// Fails if reqwest can't build an http client from those settings, e.g. because the TLS backend couldn't be
+    /// initialized.
+    pub fn new(config: ClientConfig) -> Result<Self, crate::types::ApiError> {
+        let transport = ReqwestTransport::new(&config)?;
+        Self::with_transport(config, transport)
+    }
+    /// Create a client sending requests through your own [`Transport`].
+    ///
+    /// Fails if one of the client's threads can't be spawned.
+    pub fn with_transport<T: Transport>(config: ClientConfig, transport: T) -> Result<Self, crate::types::ApiError> {
+        let (event_sender, event_receiver) = std::sync::mpsc::sync_channel::<Event>(config.request_buffer_size);
+        let (res_sender, res_receiver) = std::sync::mpsc::sync_channel::<crate::types::Response>(config.response_buffer_size);
+        let feed = Feed(Arc::new(Mutex::new(Some(event_sender.clone()))));
+        let rate = crate::rate_limit::SharedRate::new(config.rate_limit.requests_per_second);
+        let slots = Arc::new(Slots {
+            capacity: Mutex::new(crate::queue::Capacity::new(&config)),
+            freed: Condvar::new(),
+        });
+        let cache_stats = crate::cache::SharedStats::default();
+        let journal = config.journal.as_ref().map(crate::journal::JournalFile::open).transpose()?.map(Arc::new);
+
+        let (forward_sender, forward_receiver) = std::sync::mpsc::channel();
+        std::thread::Builder::new().name("perspective-rs-responses".into()).spawn(move || forward(forward_receiver, res_sender))?;
+
+        {
+            let outbox = Outbox {
+                sender: forward_sender,
+                slots: slots.clone(),
+            };
+            let dispatcher = crate::dispatch::Dispatcher::new(&config, outbox, journal.clone(), cache_stats.clone())?;
+            let feed = feed.clone();
+            let rate = rate.clone();
+            let slots = slots.clone();
+            std::thread::Builder::new()
+                .name("perspective-rs".into())
+                .spawn(move || thread(config, transport, event_receiver, feed, dispatcher, rate, slots))?;
+        }
+
+        if let Some(pending) = journal.as_ref().map(|j| j.pending()).filter(|pending| !pending.is_empty()) {
+            let feed = feed.clone();
+            let slots = slots.clone();
+            std::thread::Builder::new().name("perspective-rs-journal".into()).spawn(move || resume(feed, slots, pending))?;
+        }
+
+        Ok(Self {
+            handle: ClientHandle {
+                sender: event_sender,
+                alive: Arc::new(Alive(feed)),
+                closed: Arc::new(AtomicBool::new(false)),
+                rate,
+                next_id: Arc::new(AtomicU64::new(journal.as_ref().map_or(0, |j| j.next_id()))),
+                slots,
+                cache_stats,
+                journal,
+            },
+            receiver: Some(res_receiver),
+        })
+    }
+    /// A handle that can be cloned and moved to other threads to send requests through this client.
+    pub fn handle(&self) -> ClientHandle {
+        self.handle.clone()
+    }
+    pub fn recv(&mut self) -> Option<crate::types::Response> {
+        match self.receiver.as_mut() {
+            Some(r) => r.recv().ok(),
+            None => Some(Err(crate::types::ApiError::ReceiverTaken)),
+        }
+    }
+    /// if you want to use the receiver on another thread, you can take it
+    pub fn take_receiver(&mut self) -> Option<Receiver<crate::types::Response>> {
+        self.receiver.take()
+    }
+    /// Stop the client, returning every request that was accepted but never sent so it can be persisted or rerouted.
+    ///
+    /// Handles that are still around can no longer send requests. Tickets of returned or abandoned requests resolve
+    /// to [`crate::ApiError::ClientClosed`]. The [`crate::Journal`] is up to date once this returns.
+    pub fn shutdown(self, mode: crate::types::ShutdownMode) -> Vec<crate::types::RequestWithPriority> {
+        self.handle.closed.store(true, Ordering::Relaxed);
+        let (unsent_sender, unsent_receiver) = std::sync::mpsc::sync_channel(1);
+        if self.handle.sender.send(Event::Shutdown(mode, unsent_sender)).is_err() {
+            log::error!("failed to send shutdown signal");
+            return Vec::new();
+        }
+        let unsent = unsent_receiver.recv().unwrap_or_default();
+        if let Some(journal) = self.handle.journal.as_ref() {
+            futures::executor::block_on(journal.flush());
+        }
+        unsent
+    }
+}
+
+impl std::ops::Deref for Client {
+    type Target = ClientHandle;
+
+    fn deref(&self) -> &Self::Target {
+        &self.handle
+    }
+}
+
+impl ClientHandle {
+    /// The number of requests per second the client is currently sending at.
+    ///
+    /// This is lower than the configured rate while the client is backing off from quota errors.
+    pub fn effective_rate(&self) -> f64 {
+        self.rate.get()
+    }
+    /// How many requests were answered from the [`crate::Cache`] so far, all zero without one.
+    pub fn cache_stats(&self) -> crate::CacheStats {
+        self.cache_stats.get()
+    }
+    /// Queue a request and get a ticket that resolves to its response, waiting for room in the queues if they are full.
+    ///
+    /// The response is not sent to the shared response channel, so this can be mixed freely with `send_*` and `recv`.
+    pub fn analyze(&self, req: crate::types::Request, options: impl Into<crate::types::RequestOptions>) -> Result<ResponseTicket, SendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.acquire(options.clone(), tenant, None) {
+            Ok(permit) => permit.analyze(req),
+            Err(_) => Err(SendError(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    /// Queue a request whose response goes to the shared response channel, waiting for room in the queues if they are full.
+    pub fn send(&self, req: crate::types::Request, options: impl Into<crate::types::RequestOptions>) -> Result<crate::types::RequestId, SendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.acquire(options.clone(), tenant, None) {
+            Ok(permit) => permit.send(req),
+            Err(_) => Err(SendError(crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    pub fn send_high(&self, req: crate::types::Request) -> Result<crate::types::RequestId, SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::HIGH)
+    }
+    pub fn send_normal(&self, req: crate::types::Request) -> Result<crate::types::RequestId, SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::NORMAL)
+    }
+    pub fn send_low(&self, req: crate::types::Request) -> Result<crate::types::RequestId, SendError<crate::types::RequestWithPriority>> {
+        self.send(req, crate::types::Priority::LOW)
+    }
+    /// Like [`ClientHandle::send`], but gives the request back as [`TrySendError::Full`] if it has to wait longer than `timeout` for room.
+    pub fn send_timeout(
+        &self,
+        req: crate::types::Request,
+        options: impl Into<crate::types::RequestOptions>,
+        timeout: Duration,
+    ) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.acquire(options.clone(), tenant, Some(Instant::now() + timeout)) {
+            Ok(permit) => permit.enqueue(req, None, true),
+            Err(e) => Err(with_request(e, crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    /// Like [`ClientHandle::send`], but gives the request back right away if the queues are full.
+    pub fn try_send(&self, req: crate::types::Request, options: impl Into<crate::types::RequestOptions>) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        let options = options.into();
+        let tenant = options.tenant_of(&req);
+        match self.acquire(options.clone(), tenant, Some(Instant::now())) {
+            Ok(permit) => permit.enqueue(req, None, false),
+            Err(e) => Err(with_request(e, crate::types::RequestWithPriority::new(req, options.priority))),
+        }
+    }
+    pub fn try_send_high(&self, req: crate::types::Request) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::HIGH)
+    }
+    pub fn try_send_normal(&self, req: crate::types::Request) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::NORMAL)
+    }
+    pub fn try_send_low(&self, req: crate::types::Request) -> Result<crate::types::RequestId, TrySendError<crate::types::RequestWithPriority>> {
+        self.try_send(req, crate::types::Priority::LOW)
+    }
+    /// Block until there is room for a request with these options in the queues and hold on to it.
+    ///
+    /// Room is checked against [`ClientConfig::maximum_queue_size`], [`ClientConfig::level_queue_sizes`] and the
+    /// tenant's [`crate::Fairness::tenant_queue_size`]. Only the tenant in `options` is known here, a request that
+    /// is queued for its `community_id` instead can still be answered with [`crate::ApiError::QueueFull`].
+    pub fn reserve(&self, options: impl Into<crate::types::RequestOptions>) -> Result<Permit<'_>, SendError<()>> {
+        let options = options.into();
+        let tenant = options.tenant.clone();
+        self.acquire(options, tenant, None).map_err(|_| SendError(()))
+    }
+    /// Like [`ClientHandle::reserve`], but fails right away if the queues, the level or the tenant are full.
+    pub fn try_reserve(&self, options: impl Into<crate::types::RequestOptions>) -> Result<Permit<'_>, TrySendError<()>> {
+        let options = options.into();
+        let tenant = options.tenant.clone();
+        self.acquire(options, tenant, Some(Instant::now()))
+    }
+    fn acquire(&self, options: crate::types::RequestOptions, tenant: Option<String>, deadline: Option<Instant>) -> Result<Permit<'_>, TrySendError<()>> {
+        if self.alive.0.is_closed() || self.closed.load(Ordering::Relaxed) {
+            return Err(TrySendError::Disconnected(()));
+        }
+        let slot = self.slots.acquire(options.priority, tenant, deadline)?;
+        // the client may have started shutting down while this was waiting for room
+        if self.closed.load(Ordering::Relaxed) {
+            return Err(TrySendError::Disconnected(()));
+        }
+        Ok(Permit { client: self, options, slot })
+    }
+    /// Withdraw a request that is still waiting to be sent, its response becomes [`crate::ApiError::Cancelled`].
+    ///
+    /// Returns `false` if the request is already in flight or answered, in which case it can't be cancelled any more.
+    pub fn cancel(&self, id: crate::types::RequestId) -> bool {
+        let (reply, cancelled) = std::sync::mpsc::sync_channel(1);
+        if self.sender.send(Event::Cancel(id, reply)).is_err() {
+            return false;
+        }
+        cancelled.recv().unwrap_or(false)
+    }
+}
+
+/// Queue the requests a journal had no answer for again, as room frees up, until the client stops.
+///
+/// Their responses go to the shared response channel, the callers that sent them are long gone.
+fn resume(feed: Feed, slots: Arc<Slots>, pending: Vec<crate::journal::Record>) {
+    for record in pending {
+        let options = record.options();
+        let Ok(slot) = slots.acquire(options.priority, options.tenant_of(&record.request), None) else {
+            return;
+        };
+        // only held while sending, so it doesn't keep the background thread waiting for more
+        let Some(events) = feed.sender() else {
+            return;
+        };
+        log::info!("picking up request {} from the journal", record.id);
+        let queued = Queued::new(crate::types::RequestId(record.id), record.request, options, None, slot);
+        if events.send(Event::Request(queued)).is_err() {
+            return;
+        }
+    }
+}
+
+fn thread<T: Transport>(
+    config: ClientConfig,
+    transport: T,
+    events: Receiver<Event>,
+    feed: Feed,
+    mut dispatcher: crate::dispatch::Dispatcher<Outbox>,
+    rate: crate::rate_limit::SharedRate,
+    slots: Arc<Slots>,
+) {
+    let transport = Arc::new(transport);
+    let tape = config.cassette.as_ref().map(|c| Arc::new(crate::cassette::Tape::open(c, &config.api_key)));
+    let mut rate_limiter = crate::rate_limit::RateLimiter::new(&config.rate_limit, config.adaptive_rate.clone(), rate, Instant::now());
+    let mut in_flight = 0;
+    // set once a drain has been asked for, along with when to give up on it
+    let mut draining: Option<(Instant, SyncSender<Vec<crate::types::RequestWithPriority>>)> = None;
+
+    while !feed.is_closed() {
+        if let Some((deadline, _)) = &draining {
+            if *deadline <= Instant::now() {
+                log::info!("drain deadline passed, shutting down");
+                if let Some((_, unsent)) = draining.take() {
+                    let _ = unsent.send(take_unsent(&events, &mut dispatcher));
+                }
+                break;
+            }
+        }
+
+        dispatcher.drop_expired();
+        dispatcher.requeue_due(Instant::now());
+
+        // each request runs on its own thread so the api latency never blocks the loop
+        // a request can only go once both its tenant and the client as a whole are below their rate
+        while in_flight < config.maximum_in_flight && dispatcher.delay(Instant::now()) == Some(Duration::ZERO) && rate_limiter.try_acquire(Instant::now()) {
+            let Some(event_sender) = feed.sender() else {
+                break;
+            };
+            if let Some(queued) = dispatcher.pop(Instant::now()) {
+                let transport = transport.clone();
+                let tape = tape.clone();
+                in_flight += 1;
+                std::thread::spawn(move || {
+                    let res = get_response(&queued.request, &*transport, tape.as_deref());
+                    if event_sender.send(Event::Done(res, queued)).is_err() {
+                        log::info!("client was dropped while a request was in flight");
+                    }
+                });
+            }
+        }
+
+        // with nothing to wait for the thread sleeps until an event arrives, or every sender is gone
+        let now = Instant::now();
+        let timeout = [
+            dispatcher.delay(now).filter(|_| in_flight < config.maximum_in_flight).map(|delay| delay.max(rate_limiter.delay(now))),
+            dispatcher.next_retry().map(|at| at.saturating_duration_since(now)),
+            draining.as_ref().map(|(deadline, _)| deadline.saturating_duration_since(now)),
+            dispatcher.next_expiry().map(|at| at.saturating_duration_since(now)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+
+        let event = if draining.is_some() && dispatcher.is_empty() && in_flight == 0 {
+            // the client is blocked in shutdown, so once the channel is empty nothing else can arrive
+            match events.try_recv() {
+                Ok(event) => Ok(event),
+                Err(_) => {
+                    log::info!("drained all requests");
+                    if let Some((_, unsent)) = draining.take() {
+                        let _ = unsent.send(Vec::new());
+                    }
+                    break;
+                }
+            }
+        } else {
+            match timeout {
+                Some(timeout) => events.recv_timeout(timeout),
+                None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            }
+        };
+
+        match event {
+            Ok(Event::Request(queued)) => dispatcher.accept(queued),
+            Ok(Event::Done(res, queued)) => {
+                in_flight -= 1;
+                dispatcher.settle(res, queued, &mut rate_limiter);
+            }
+            Ok(Event::Cancel(id, reply)) => {
+                let _ = reply.send(dispatcher.cancel(id));
+            }
+            Ok(Event::Shutdown(crate::types::ShutdownMode::Drain { deadline }, unsent)) => {
+                log::info!("draining requests");
+                draining = Some((Instant::now() + deadline, unsent));
+            }
+            Ok(Event::Shutdown(crate::types::ShutdownMode::Immediate, unsent)) => {
+                log::info!("shutting down");
+                let _ = unsent.send(take_unsent(&events, &mut dispatcher));
+                break;
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+
+    // wakes up anyone waiting for room
+    slots.close();
+    log::info!("killing thread");
+}
+
+/// Everything that was accepted but not sent, including what is still in the channel, highest priority first.
+fn take_unsent(events: &Receiver<Event>, dispatcher: &mut crate::dispatch::Dispatcher<Outbox>) -> Vec<crate::types::RequestWithPriority> {
+    let arrived = events.try_iter().filter_map(|event| match event {
+        Event::Request(queued) => Some(queued),
+        _ => None,
+    });
+    dispatcher.take_unsent(arrived)
+}
+
+/// Move responses into the shared response channel as its receiver makes room for them.
+///
+/// Requests sent with `send_*` keep their room in the queues until their response is in the channel, so a consumer
+/// that falls behind makes senders wait for room rather than responses pile up.
+fn forward(responses: Receiver<(crate::types::Response, Option<Slot>)>, sender: SyncSender<crate::types::Response>) {
+    for (res, _slot) in responses {
+        if let Err(e) = sender.send(res) {
+            log::error!("failed to send response: {}", e);
+        }
+    }
+}
+
+fn get_response<T: Transport>(req: &crate::types::Request, transport: &T, tape: Option<&crate::cassette::Tape>) -> crate::types::Response {
+    let body = serde_json::to_vec(req).map_err(|e| crate::types::ApiError::Json(e, String::new()))?;
+
+    let res = match tape.filter(|t| t.is_replaying()) {
+        Some(tape) => tape.replay(&body)?,
+        None => transport.send(body.clone())?,
+    };
+
+    if let Some(tape) = tape {
+        tape.record(&body, &res);
+    }
+
+    crate::types::RawApiResponse::from_http(res.status, &res.headers, res.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{Fault, FakeServer};
+    use crate::types::{Attribute, AttributeOptions, Priority, Request, RequestBuilder};
+
+    fn request(text: &str) -> Request {
+        RequestBuilder::default()
+            .comment(text)
+            .add_attribute(Attribute::Toxicity, AttributeOptions::default())
+            .build()
+            .unwrap()
+    }
+
+    /// A config that sends one request and then holds the rest back for long enough to see them queued.
+    fn slow_config(server: &FakeServer) -> crate::ClientConfigBuilder {
+        let mut builder = server.config_builder();
+        builder.rate_limit(crate::RateLimit {
+            requests_per_second: 0.1,
+            burst: 1,
+            ..Default::default()
+        });
+        builder
+    }
+
+    #[test]
+    fn test_analyze_against_fake_server() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::ServerError(500));
+        let retry_policy = crate::RetryPolicyBuilder::default().base_delay(Duration::from_millis(1)).build().unwrap();
+        let client = Client::new(server.config_builder().retry_policy(retry_policy).build().unwrap()).unwrap();
+        let request = RequestBuilder::default()
+            .comment("damn it")
+            .add_attribute(Attribute::Profanity, AttributeOptions::default())
+            .build()
+            .unwrap();
+
+        let res = client.analyze(request, Priority::HIGH).unwrap().wait().unwrap();
+
+        assert!(res.attribute_scores[&Attribute::Profanity].summary_score.value > 0.7);
+        assert_eq!(res.attempts, 2);
+    }
+
+    #[test]
+    fn test_send_and_recv() {
+        let server = FakeServer::start();
+        let mut client = Client::new(server.config_builder().build().unwrap()).unwrap();
+        let with_token = |token: &str| {
+            let mut request = request(token);
+            request.client_token = Some(token.into());
+            request
+        };
+
+        client.send_high(with_token("high")).unwrap();
+        client.send_normal(with_token("normal")).unwrap();
+        client.send_low(with_token("low")).unwrap();
+
+        let mut tokens = (0..3).map(|_| client.recv().unwrap().unwrap().client_token.unwrap()).collect::<Vec<_>>();
+        tokens.sort();
+        assert_eq!(tokens, ["high", "low", "normal"]);
+        assert_eq!(server.request_count(), 3);
+    }
+
+    #[test]
+    fn test_drain_finishes_queued_requests() {
+        let server = FakeServer::start();
+        let client = Client::new(server.config_builder().build().unwrap()).unwrap();
+
+        let tickets = vec![client.analyze(request("one"), Priority::NORMAL).unwrap(), client.analyze(request("two"), Priority::LOW).unwrap()];
+        let unsent = client.shutdown(crate::types::ShutdownMode::Drain { deadline: Duration::from_secs(5) });
+
+        assert!(unsent.is_empty());
+        for ticket in tickets {
+            assert!(ticket.wait().is_ok());
+        }
+        assert_eq!(server.request_count(), 2);
+    }
+
+    #[test]
+    fn test_handles_cannot_send_once_draining() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let client = Client::new(server.config_builder().build().unwrap()).unwrap();
+        let handle = client.handle();
+        let held = client.analyze(request("held"), Priority::NORMAL).unwrap();
+
+        let shutdown = std::thread::spawn(move || client.shutdown(crate::types::ShutdownMode::Drain { deadline: Duration::from_secs(5) }));
+        // requests may still get in before the drain starts, but once one is refused every later one is too
+        let mut accepted = Vec::new();
+        let refused = loop {
+            match handle.analyze(request("late"), Priority::NORMAL) {
+                Ok(ticket) => accepted.push(ticket),
+                Err(e) => break e,
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(refused.0.comment.text, "late");
+        assert!(matches!(handle.try_send_high(request("later")), Err(TrySendError::Disconnected(_))));
+
+        server.release();
+        assert!(shutdown.join().unwrap().is_empty());
+        assert!(held.wait().is_ok());
+        for ticket in accepted {
+            assert!(ticket.wait().is_ok());
+        }
+    }
+
+    #[test]
+    fn test_immediate_shutdown_returns_unsent_requests() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).build().unwrap()).unwrap();
+        let request = request("hello");
+        client.analyze(request.clone(), Priority::NORMAL).unwrap().wait().unwrap();
+
+        let ticket = client.analyze(request, Priority::LOW).unwrap();
+        let unsent = client.shutdown(crate::types::ShutdownMode::Immediate);
+
+        assert_eq!(unsent.len(), 1);
+        assert!(matches!(ticket.wait(), Err(crate::types::ApiError::ClientClosed)));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[test]
+    fn test_try_send_when_queue_is_full() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).maximum_queue_size(1).build().unwrap()).unwrap();
+        let request = request("hello");
+        client.analyze(request.clone(), Priority::NORMAL).unwrap().wait().unwrap();
+
+        client.try_send_low(request.clone()).unwrap();
+
+        assert!(matches!(client.try_send_high(request.clone()), Err(TrySendError::Full(_))));
+        assert!(matches!(client.send_timeout(request, Priority::HIGH, Duration::from_millis(50)), Err(TrySendError::Full(_))));
+    }
+
+    #[test]
+    fn test_unread_responses_hold_room_without_blocking_tickets() {
+        let server = FakeServer::start();
+        let mut client = Client::new(server.config_builder().response_buffer_size(1).maximum_queue_size(2).build().unwrap()).unwrap();
+
+        client.send_normal(request("one")).unwrap();
+        client.send_normal(request("two")).unwrap();
+        client.analyze(request("ticket"), Priority::NORMAL).unwrap().wait().unwrap();
+        client.send_normal(request("three")).unwrap();
+
+        // "two" and "three" keep their room until "one" is read and makes space in the channel
+        assert!(matches!(client.send_timeout(request("four"), Priority::NORMAL, Duration::from_millis(100)), Err(TrySendError::Full(_))));
+        for _ in 0..3 {
+            client.recv().unwrap().unwrap();
+        }
+        client.send_timeout(request("four"), Priority::NORMAL, Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_full_level_or_tenant_is_reported_when_sending() {
+        let server = FakeServer::start();
+        let fairness = crate::FairnessBuilder::default().tenant_queue_size(1).build().unwrap();
+        let client = Client::new(slow_config(&server).level_queue_size(Priority::LOW, 1).fairness(fairness).build().unwrap()).unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).unwrap().wait().unwrap();
+        let noisy = crate::types::RequestOptions::new(Priority::HIGH).tenant("noisy");
+
+        client.try_send_low(request("low")).unwrap();
+        client.try_send(request("noisy"), noisy.clone()).unwrap();
+
+        assert!(matches!(client.try_send_low(request("full level")), Err(TrySendError::Full(_))));
+        assert!(matches!(client.try_send(request("full tenant"), noisy.clone()), Err(TrySendError::Full(_))));
+        assert!(client.try_reserve(noisy).is_err());
+        client.try_reserve(Priority::HIGH).unwrap().send(request("other level and tenant")).unwrap();
+    }
+
+    #[test]
+    fn test_handles_send_from_many_threads() {
+        let server = FakeServer::start();
+        let client = Client::new(server.config_builder().build().unwrap()).unwrap();
+
+        let threads = (0..4)
+            .map(|i| {
+                let handle = client.handle();
+                let request = request(&format!("thread {}", i));
+                std::thread::spawn(move || handle.analyze(request, Priority::NORMAL).unwrap().wait())
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            assert!(thread.join().unwrap().is_ok());
+        }
+
+        // the client going away leaves the handles working
+        let handle = client.handle();
+        drop(client);
+        assert!(handle.analyze(request("after drop"), Priority::NORMAL).unwrap().wait().is_ok());
+        assert_eq!(server.request_count(), 5);
+    }
+
+    #[test]
+    fn test_dropping_the_client_stops_it_right_away() {
+        let server = FakeServer::start();
+        let client = Client::new(slow_config(&server).build().unwrap()).unwrap();
+        client.analyze(request("sent"), Priority::NORMAL).unwrap().wait().unwrap();
+        let queued = client.analyze(request("queued"), Priority::NORMAL).unwrap();
+
+        let dropped = Instant::now();
+        drop(client);
+        assert!(matches!(queued.wait(), Err(crate::types::ApiError::ClientClosed)));
+        assert!(dropped.elapsed() < Duration::from_millis(50));
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[test]
+    fn test_identical_requests_are_coalesced() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Latency(Duration::from_millis(200)));
+        let client = Client::new(server.config_builder().build().unwrap()).unwrap();
+        let request = request("lol");
+
+        let tickets = (0..3).map(|_| client.analyze(request.clone(), Priority::NORMAL).unwrap()).collect::<Vec<_>>();
+
+        for ticket in tickets {
+            assert!(ticket.wait().is_ok());
+        }
+        assert_eq!(server.request_count(), 1);
+    }
+
+    #[test]
+    fn test_urgent_request_is_not_held_back_by_an_identical_queued_one() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let client = Client::new(server.config_builder().maximum_in_flight(1).build().unwrap()).unwrap();
+        let held = client.analyze(request("held"), Priority::HIGH).unwrap();
+        while server.request_count() < 1 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let other = client.analyze(request("other"), Priority::LOW).unwrap();
+        let low = client.analyze(request("lol"), Priority::LOW).unwrap();
+        let high = client.analyze(request("lol"), Priority::HIGH).unwrap();
+        // cancels are handled in order with the requests, so everything above is queued once this returns
+        let barrier = client.analyze(request("barrier"), Priority::LOW).unwrap();
+        assert!(client.cancel(barrier.id()));
+        server.release();
+
+        held.wait().unwrap();
+        high.wait().unwrap();
+        low.wait().unwrap();
+        other.wait().unwrap();
+        let texts = server.requests().iter().map(|r| r.body["comment"]["text"].as_str().unwrap().to_owned()).collect::<Vec<_>>();
+        assert_eq!(texts, ["held", "lol", "other"]);
+    }
+
+    #[test]
+    fn test_failed_requests_go_to_dead_letters() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Malformed);
+        let journal = crate::journal::TestJournal::new("sync-dead-letters");
+        let client = Client::new(server.config_builder().journal(journal.0.clone()).build().unwrap()).unwrap();
+
+        assert!(client.analyze(request("rejected"), Priority::NORMAL).unwrap().wait().is_err());
+        assert!(client.analyze(request("accepted"), Priority::NORMAL).unwrap().wait().is_ok());
+        client.shutdown(crate::types::ShutdownMode::Immediate);
+
+        let dead_letters = journal.dead_letters();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0]["request"]["comment"]["text"], "rejected");
+        assert!(crate::journal::JournalFile::open(&journal.0).unwrap().pending().is_empty());
+    }
+
+    #[test]
+    fn test_requests_that_were_never_sent_are_not_dead_lettered() {
+        let server = FakeServer::start();
+        server.push_fault(Fault::Hold);
+        let journal = crate::journal::TestJournal::new("sync-never-sent");
+        let config = server.config_builder().maximum_in_flight(1).level_queue_size(Priority::LOW, 1).journal(journal.0.clone()).build().unwrap();
+        let client = Client::new(config).unwrap();
+        let held = client.analyze(request("held"), Priority::NORMAL).unwrap();
+        while server.request_count() < 1 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        let leader = client.analyze(request("lol"), Priority::NORMAL).unwrap();
+        // follows the leader and gives its room back, which the filler takes
+        let follower = client.analyze(request("lol"), Priority::LOW).unwrap();
+        let filler = client.analyze(request("filler"), Priority::LOW).unwrap();
+        assert!(client.cancel(leader.id()));
+        server.release();
+
+        assert!(matches!(leader.wait(), Err(crate::types::ApiError::Cancelled(_))));
+        assert!(matches!(follower.wait(), Err(crate::types::ApiError::QueueFull)));
+        assert!(held.wait().is_ok());
+        assert!(filler.wait().is_ok());
+        client.shutdown(crate::types::ShutdownMode::Immediate);
+        assert!(journal.dead_letters().is_empty());
+        assert!(crate::journal::JournalFile::open(&journal.0).unwrap().pending().is_empty());
+    }
+}
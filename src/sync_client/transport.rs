@@ -0,0 +1,67 @@
+use std::sync::Arc;
+
+use crate::transport::TransportResponse;
+use crate::ClientConfig;
+
+/// Sends serialized requests to the API, see [`ReqwestTransport`] for the default.
+///
+/// Implement this to put something other than reqwest between the client and the API, e.g. an in-memory fake.
+pub trait Transport: Send + Sync + 'static {
+    /// Send a serialized [`crate::Request`] and return the status and body the API answered with.
+    fn send(&self, body: Vec<u8>) -> Result<TransportResponse, crate::types::ApiError>;
+}
+
+/// The default transport, posting requests to the configured endpoint with blocking reqwest.
+#[derive(Clone, Debug)]
+pub struct ReqwestTransport {
+    client: reqwest::blocking::Client,
+    endpoint: Arc<crate::endpoint::Endpoint>,
+}
+
+impl ReqwestTransport {
+    /// Build a reqwest client from [`ClientConfig::http`].
+    pub fn new(config: &ClientConfig) -> Result<Self, crate::types::ApiError> {
+        let mut builder = reqwest::blocking::Client::builder().tls_certs_merge(config.http.root_certificates()?);
+        if let Some(timeout) = config.http.connect_timeout {
+            builder = builder.connect_timeout(timeout);
+        }
+        if let Some(timeout) = config.http.request_timeout {
+            builder = builder.timeout(timeout);
+        }
+        if let Some(proxy) = config.http.proxy() {
+            builder = builder.proxy(proxy?);
+        }
+        if let Some(user_agent) = config.http.user_agent.as_ref() {
+            builder = builder.user_agent(user_agent.as_str());
+        }
+
+        Ok(Self::with_client(builder.build()?, config))
+    }
+
+    /// Use a reqwest client you built yourself, [`ClientConfig::http`] is ignored.
+    pub fn with_client(client: reqwest::blocking::Client, config: &ClientConfig) -> Self {
+        Self {
+            client,
+            endpoint: Arc::new(crate::endpoint::Endpoint::new(config)),
+        }
+    }
+}
+
+impl Transport for ReqwestTransport {
+    fn send(&self, body: Vec<u8>) -> Result<TransportResponse, crate::types::ApiError> {
+        let res = self
+            .client
+            .post(&self.endpoint.url)
+            .headers(self.endpoint.headers.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()?;
+
+        let status = res.status();
+        let headers = res.headers().clone();
+
+        let body = res.text()?;
+
+        Ok(TransportResponse { status, headers, body })
+    }
+}
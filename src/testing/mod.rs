@@ -0,0 +1,414 @@
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use crate::types::Attribute;
+
+/// The score given to every requested attribute that no keyword matched.
+const BASE_SCORE: f64 = 0.02;
+
+/// Something going wrong for a single request.
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// HTTP 429 with a `RESOURCE_EXHAUSTED` body, optionally with a `Retry-After` header in seconds.
+    QuotaExceeded { retry_after: Option<u64> },
+    /// A Google error body with this HTTP status, e.g. 500 or 503.
+    ServerError(u16),
+    /// An HTTP status with a body that isn't JSON, like a proxy would send.
+    Status(u16),
+    /// Wait this long before answering normally.
+    Latency(Duration),
+    /// Don't answer until [`FakeServer::release`] is called, which keeps the request in flight for as long as a test
+    /// needs it to be.
+    Hold,
+    /// HTTP 200 with a body that isn't valid JSON.
+    Malformed,
+    /// HTTP 200 with only `languages` and `clientToken`, see [`crate::EmptyApiResponse`].
+    EmptyResponse,
+}
+
+/// A request as it was received by the server.
+#[derive(Clone, Debug)]
+pub struct ReceivedRequest {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: serde_json::Value,
+}
+
+impl ReceivedRequest {
+    /// The value of a header, matched case insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+    }
+}
+
+#[derive(Default)]
+struct State {
+    keywords: Vec<(String, Attribute, f64)>,
+    faults: VecDeque<Fault>,
+    latency: Duration,
+    api_key: Option<String>,
+    requests: Vec<ReceivedRequest>,
+    released: bool,
+}
+
+/// A local HTTP server speaking the `comments:analyze` API, stopped when dropped.
+///
+/// ```no_run
+/// let server = perspective_rs::testing::FakeServer::start();
+/// let config = server.config_builder().build().unwrap();
+/// ```
+///
+/// Scores are deterministic: every sentence of the comment is scored on its own by looking for keywords (see
+/// [`FakeServer::keyword`]), the summary score of an attribute is the highest score of any sentence. Faults can be
+/// queued with [`FakeServer::push_fault`] and are used up one per request, in order.
+pub struct FakeServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    // notified when held requests are released
+    release: Arc<Condvar>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl FakeServer {
+    /// Start a server on a random local port, with the default keywords.
+    pub fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind fake server");
+        let addr = listener.local_addr().expect("failed to get fake server address");
+        let state = Arc::new(Mutex::new(State {
+            keywords: default_keywords(),
+            ..Default::default()
+        }));
+        let release = Arc::new(Condvar::new());
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        {
+            let state = state.clone();
+            let release = release.clone();
+            let stopped = stopped.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if stopped.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            let state = state.clone();
+                            let release = release.clone();
+                            std::thread::spawn(move || {
+                                if let Err(e) = handle(stream, &state, &release) {
+                                    log::error!("fake server failed to handle connection: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) => log::error!("fake server failed to accept connection: {}", e),
+                    }
+                }
+            });
+        }
+
+        Self { addr, state, release, stopped }
+    }
+
+    /// The base url to put in [`crate::ClientConfig::base_url`].
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// A config builder pointed at this server, with a placeholder key and a rate limit fast enough for tests.
+    pub fn config_builder(&self) -> crate::ClientConfigBuilder {
+        let mut builder = crate::ClientConfigBuilder::default();
+        builder
+            .api_key("fake-api-key".into())
+            .base_url(self.url())
+            .rate_limit(crate::RateLimit {
+                requests_per_second: 1000.0,
+                burst: 1000,
+                ..Default::default()
+            });
+        builder
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Give any sentence containing `word` (case insensitive) at least `score` for `attribute`.
+    pub fn keyword(&self, word: impl Into<String>, attribute: Attribute, score: f64) -> &Self {
+        self.state().keywords.push((word.into().to_lowercase(), attribute, score));
+        self
+    }
+
+    /// Queue a fault for the next request that doesn't already have one.
+    pub fn push_fault(&self, fault: Fault) -> &Self {
+        self.state().faults.push_back(fault);
+        self
+    }
+
+    /// Delay every response by this much.
+    pub fn set_latency(&self, latency: Duration) -> &Self {
+        self.state().latency = latency;
+        self
+    }
+
+    /// Reject requests that don't send this key with an `API_KEY_INVALID` error.
+    pub fn require_api_key(&self, key: impl Into<String>) -> &Self {
+        self.state().api_key = Some(key.into());
+        self
+    }
+
+    /// Every request received so far, in order.
+    pub fn requests(&self) -> Vec<ReceivedRequest> {
+        self.state().requests.clone()
+    }
+
+    pub fn request_count(&self) -> usize {
+        self.state().requests.len()
+    }
+
+    /// Answer every request held by [`Fault::Hold`], including ones that arrive later.
+    pub fn release(&self) {
+        self.state().released = true;
+        self.release.notify_all();
+    }
+}
+
+impl Drop for FakeServer {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.release();
+        // wake the accept loop up so it notices
+        let _ = TcpStream::connect(self.addr);
+    }
+}
+
+fn default_keywords() -> Vec<(String, Attribute, f64)> {
+    [
+        ("idiot", Attribute::Toxicity, 0.92),
+        ("idiot", Attribute::Insult, 0.95),
+        ("stupid", Attribute::Toxicity, 0.85),
+        ("stupid", Attribute::Insult, 0.88),
+        ("hate", Attribute::Toxicity, 0.7),
+        ("hate", Attribute::IdentityAttack, 0.6),
+        ("damn", Attribute::Profanity, 0.75),
+        ("damn", Attribute::Toxicity, 0.55),
+        ("kill", Attribute::Threat, 0.9),
+        ("kill", Attribute::SevereToxicity, 0.6),
+        ("buy now", Attribute::Spam, 0.97),
+        ("gorgeous", Attribute::Flirtation, 0.8),
+    ]
+    .into_iter()
+    .map(|(w, a, s)| (w.to_string(), a, s))
+    .collect()
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>, release: &Condvar) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default().to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+
+    let length = headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, v)| v.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    let body = serde_json::from_slice::<serde_json::Value>(&body).unwrap_or(serde_json::Value::Null);
+
+    let request = ReceivedRequest { path, headers, body };
+    let (fault, latency, api_key) = {
+        let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+        state.requests.push(request.clone());
+        (state.faults.pop_front(), state.latency, state.api_key.clone())
+    };
+
+    std::thread::sleep(latency);
+    if let Some(Fault::Latency(latency)) = fault {
+        std::thread::sleep(latency);
+    }
+    if let Some(Fault::Hold) = fault {
+        let state = state.lock().unwrap_or_else(|e| e.into_inner());
+        let _state = release.wait_while(state, |s| !s.released).unwrap_or_else(|e| e.into_inner());
+    }
+
+    let (status, extra_headers, body) = match fault {
+        Some(Fault::QuotaExceeded { retry_after }) => (
+            429,
+            retry_after.map(|s| format!("Retry-After: {}\r\n", s)).unwrap_or_default(),
+            error_body(429, "Quota exceeded for quota metric 'Analysis requests'.", "RESOURCE_EXHAUSTED", serde_json::json!([])),
+        ),
+        Some(Fault::ServerError(code)) => (code, String::new(), error_body(code, "The service is currently unavailable.", "UNAVAILABLE", serde_json::json!([]))),
+        Some(Fault::Status(code)) => (code, String::new(), "<html><body>bad gateway</body></html>".to_string()),
+        Some(Fault::Malformed) => (200, String::new(), "{\"attributeScores\": {".to_string()),
+        Some(Fault::EmptyResponse) => (
+            200,
+            String::new(),
+            serde_json::json!({ "languages": ["en"], "clientToken": request.body.get("clientToken") }).to_string(),
+        ),
+        Some(Fault::Latency(_)) | Some(Fault::Hold) | None => match api_key {
+            Some(key) if request.header("x-goog-api-key") != Some(key.as_str()) => (
+                400,
+                String::new(),
+                error_body(
+                    400,
+                    "API key not valid. Please pass a valid API key.",
+                    "INVALID_ARGUMENT",
+                    serde_json::json!([{
+                        "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+                        "reason": "API_KEY_INVALID",
+                        "domain": "googleapis.com",
+                        "metadata": { "service": "commentanalyzer.googleapis.com" }
+                    }]),
+                ),
+            ),
+            _ => {
+                let keywords = state.lock().unwrap_or_else(|e| e.into_inner()).keywords.clone();
+                match analyze(&request.body, &keywords) {
+                    Ok(body) => (200, String::new(), body.to_string()),
+                    Err(body) => (400, String::new(), body),
+                }
+            }
+        },
+    };
+
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n{}\r\n{}",
+        status,
+        reason(status),
+        body.len(),
+        extra_headers,
+        body
+    )?;
+    stream.flush()
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+fn error_body(code: u16, message: &str, status: &str, details: serde_json::Value) -> String {
+    serde_json::json!({ "error": { "code": code, "message": message, "status": status, "details": details } }).to_string()
+}
+
+fn perspective_error(message: &str, error_type: &str) -> String {
+    error_body(
+        400,
+        message,
+        "INVALID_ARGUMENT",
+        serde_json::json!([{ "@type": "type.googleapis.com/google.commentanalyzer.v1alpha1.Error", "errorType": error_type }]),
+    )
+}
+
+/// Score a request body the way the real API would shape it, or return the error body to send.
+fn analyze(body: &serde_json::Value, keywords: &[(String, Attribute, f64)]) -> Result<serde_json::Value, String> {
+    let text = body.pointer("/comment/text").and_then(|t| t.as_str()).unwrap_or_default();
+    if text.is_empty() {
+        return Err(perspective_error("Comment must be non-empty.", "COMMENT_EMPTY"));
+    }
+    if text.len() > 20_000 {
+        return Err(perspective_error("Comment text too long.", "COMMENT_TOO_LONG"));
+    }
+
+    let requested = body.get("requestedAttributes").and_then(|a| a.as_object()).cloned().unwrap_or_default();
+    if requested.is_empty() {
+        return Err(error_body(400, "Missing requested_attributes.", "INVALID_ARGUMENT", serde_json::json!([])));
+    }
+
+    let spans = sentences(text);
+    let span_annotations = body.get("spanAnnotations").and_then(|s| s.as_bool()).unwrap_or(false);
+
+    let mut scores = serde_json::Map::new();
+    for (name, options) in requested.iter() {
+        let Ok(attribute) = serde_json::from_value::<Attribute>(serde_json::Value::String(name.clone())) else {
+            return Err(error_body(400, &format!("Unknown requested attribute: {}", name), "INVALID_ARGUMENT", serde_json::json!([])));
+        };
+
+        let span_scores = spans
+            .iter()
+            .map(|(begin, end, sentence)| {
+                let sentence = sentence.to_lowercase();
+                let score = keywords
+                    .iter()
+                    .filter(|(word, a, _)| *a == attribute && sentence.contains(word.as_str()))
+                    .map(|(_, _, score)| *score)
+                    .fold(BASE_SCORE, f64::max);
+                (*begin, *end, score)
+            })
+            .collect::<Vec<_>>();
+        let summary = span_scores.iter().map(|(_, _, s)| *s).fold(BASE_SCORE, f64::max);
+
+        let threshold = options
+            .get("scoreThreshold")
+            .or_else(|| options.get("score_threshold"))
+            .and_then(|t| t.as_f64())
+            .unwrap_or(0.0);
+        if summary < threshold {
+            continue;
+        }
+
+        let mut score = serde_json::json!({ "summaryScore": { "value": summary, "type": "PROBABILITY" } });
+        if span_annotations {
+            score["spanScores"] = span_scores
+                .into_iter()
+                .map(|(begin, end, value)| serde_json::json!({ "begin": begin, "end": end, "score": { "value": value, "type": "PROBABILITY" } }))
+                .collect();
+        }
+        scores.insert(name.clone(), score);
+    }
+
+    let languages = body.get("languages").cloned().unwrap_or_else(|| serde_json::json!(["en"]));
+    let mut response = serde_json::json!({ "attributeScores": scores, "languages": languages, "detectedLanguages": ["en"] });
+    if let Some(token) = body.get("clientToken") {
+        response["clientToken"] = token.clone();
+    }
+    Ok(response)
+}
+
+/// Split text into sentences, with begin and end as character indices like the real span scores.
+fn sentences(text: &str) -> Vec<(usize, usize, String)> {
+    let mut spans = Vec::new();
+    let mut begin = 0;
+    let mut current = String::new();
+    for (i, c) in text.chars().enumerate() {
+        current.push(c);
+        if matches!(c, '.' | '!' | '?') {
+            spans.push((begin, i + 1, std::mem::take(&mut current)));
+            begin = i + 1;
+        }
+    }
+    if !current.trim().is_empty() {
+        spans.push((begin, text.chars().count(), current));
+    }
+    spans
+}
@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+/// Settings for the default reqwest based transport.
+#[derive(derive_builder::Builder, Clone, Default, PartialEq)]
+#[builder(build_fn(validate = "Self::validate"))]
+pub struct HttpConfig {
+    /// How long to wait for a connection to the API to be established.
+    #[builder(default, setter(strip_option))]
+    pub connect_timeout: Option<Duration>,
+    /// How long a whole request may take, from connecting until the body has been read.
+    #[builder(default, setter(strip_option))]
+    pub request_timeout: Option<Duration>,
+    /// A proxy url all requests go through, e.g. `http://proxy.corp:3128`.
+    #[builder(default, setter(into, strip_option))]
+    pub proxy: Option<String>,
+    #[builder(default, setter(into, strip_option))]
+    pub user_agent: Option<String>,
+    /// Extra PEM encoded root certificates (or bundles of them) to trust, on top of the system ones.
+    #[builder(default)]
+    pub root_certificates: Vec<Vec<u8>>,
+}
+
+impl HttpConfig {
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if let Some(Duration::ZERO) = self.connect_timeout {
+            return Err("connect timeout cannot be 0".into());
+        }
+
+        if let Some(Duration::ZERO) = self.request_timeout {
+            return Err("request timeout cannot be 0".into());
+        }
+
+        if let Some(proxy) = self.proxy.as_ref() {
+            reqwest::Proxy::all(proxy.as_str()).map_err(|e| format!("invalid proxy: {}", crate::types::ApiError::from(e)))?;
+        }
+
+        if let Some(user_agent) = self.user_agent.as_ref() {
+            user_agent.parse::<reqwest::header::HeaderValue>().map_err(|e| format!("invalid user agent: {}", e))?;
+        }
+
+        for certificate in self.root_certificates.iter() {
+            match reqwest::Certificate::from_pem_bundle(certificate) {
+                Ok(certificates) if certificates.is_empty() => return Err("root certificate does not contain any certificates".into()),
+                Ok(_) => {}
+                Err(e) => return Err(format!("invalid root certificate: {}", e)),
+            }
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn proxy(&self) -> Option<reqwest::Result<reqwest::Proxy>> {
+        self.proxy.as_ref().map(|p| reqwest::Proxy::all(p.as_str()))
+    }
+
+    pub(crate) fn root_certificates(&self) -> reqwest::Result<Vec<reqwest::Certificate>> {
+        let mut certificates = Vec::new();
+        for bundle in self.root_certificates.iter() {
+            certificates.extend(reqwest::Certificate::from_pem_bundle(bundle)?);
+        }
+        Ok(certificates)
+    }
+}
+
+// the proxy url may hold credentials
+impl std::fmt::Debug for HttpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let proxy = self.proxy.as_ref().map(|proxy| match reqwest::Url::parse(proxy) {
+            Ok(mut url) => {
+                crate::api_key::scrub_url(&mut url);
+                url.to_string()
+            }
+            Err(_) => "<redacted>".to_string(),
+        });
+        f.debug_struct("HttpConfig")
+            .field("connect_timeout", &self.connect_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("proxy", &proxy)
+            .field("user_agent", &self.user_agent)
+            .field("root_certificates", &self.root_certificates)
+            .finish()
+    }
+}
+
+impl HttpConfigBuilder {
+    fn validate(&self) -> Result<(), String> {
+        HttpConfig {
+            connect_timeout: self.connect_timeout.flatten(),
+            request_timeout: self.request_timeout.flatten(),
+            proxy: self.proxy.clone().flatten(),
+            user_agent: self.user_agent.clone().flatten(),
+            root_certificates: self.root_certificates.clone().unwrap_or_default(),
+        }
+        .validate()
+    }
+
+    /// Trust an extra PEM encoded root certificate or bundle.
+    pub fn root_certificate(&mut self, pem: impl Into<Vec<u8>>) -> &mut Self {
+        self.root_certificates.get_or_insert_with(Vec::new).push(pem.into());
+        self
+    }
+}
+
+/// What a transport got back from the API, before any parsing.
+#[derive(Clone, Debug)]
+pub struct TransportResponse {
+    pub status: reqwest::StatusCode,
+    pub headers: reqwest::header::HeaderMap,
+    pub body: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_config_builder_validate() {
+        assert!(HttpConfigBuilder::default().proxy("not a proxy url").build().is_err());
+        assert!(HttpConfigBuilder::default().root_certificate("not a certificate").build().is_err());
+        assert!(HttpConfigBuilder::default().request_timeout(Duration::ZERO).build().is_err());
+        assert!(HttpConfigBuilder::default()
+            .proxy("http://proxy.corp:3128")
+            .connect_timeout(Duration::from_secs(5))
+            .user_agent("moderation-bot/1.0")
+            .build()
+            .is_ok());
+    }
+}
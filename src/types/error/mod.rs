@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use super::ApiError;
+
+// example error response
+// {
+//   "error": {
+//     "code": 400,
+//     "message": "API key not valid. Please pass a valid API key.",
+//     "status": "INVALID_ARGUMENT",
+//     "details": [
+//       {
+//         "@type": "type.googleapis.com/google.rpc.ErrorInfo",
+//         "reason": "API_KEY_INVALID",
+//         "domain": "googleapis.com",
+//         "metadata": {
+//           "service": "commentanalyzer.googleapis.com"
+//         }
+//       }
+//     ]
+//   }
+// }
+
+// perspective specific errors come with their own detail type
+// {
+//   "@type": "type.googleapis.com/google.commentanalyzer.v1alpha1.Error",
+//   "errorType": "LANGUAGE_NOT_SUPPORTED_BY_ATTRIBUTE",
+//   "languageNotSupportedByAttributeError": {
+//     "detectedLanguages": ["es"],
+//     "attribute": "SPAM"
+//   }
+// }
+
+/// The error body returned by Google APIs.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ApiErrorBody {
+    pub error: ApiErrorBodyError,
+}
+
+impl std::fmt::Display for ApiErrorBody {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error.message)
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ApiErrorBodyError {
+    /// The HTTP status code.
+    pub code: u16,
+    pub message: String,
+    /// The `google.rpc.Code` name, e.g. `INVALID_ARGUMENT` or `RESOURCE_EXHAUSTED`.
+    pub status: String,
+    #[serde(default)]
+    pub details: Vec<ApiErrorDetail>,
+}
+
+/// A single entry of `error.details`. Which fields are set depends on `type_`.
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct ApiErrorDetail {
+    #[serde(rename = "@type")]
+    pub type_: String,
+    /// Set on `google.rpc.ErrorInfo`, e.g. `API_KEY_INVALID` or `RATE_LIMIT_EXCEEDED`.
+    #[serde(default)]
+    pub reason: Option<String>,
+    #[serde(default)]
+    pub domain: Option<String>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+    /// Set on `google.rpc.RetryInfo`, e.g. `"30s"`.
+    #[serde(rename = "retryDelay", default)]
+    pub retry_delay: Option<String>,
+    /// Set on perspective's own error type, e.g. `COMMENT_EMPTY`.
+    #[serde(rename = "errorType", default)]
+    pub error_type: Option<String>,
+    #[serde(rename = "languageNotSupportedByAttributeError", default)]
+    pub language_not_supported_by_attribute_error: Option<LanguageNotSupportedByAttributeError>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct LanguageNotSupportedByAttributeError {
+    pub attribute: String,
+    #[serde(rename = "detectedLanguages", default)]
+    pub detected_languages: Vec<String>,
+    #[serde(rename = "requestedLanguages", default)]
+    pub requested_languages: Vec<String>,
+}
+
+impl ApiErrorBody {
+    pub(crate) fn is_quota_exceeded(&self) -> bool {
+        self.error.code == 429 || self.error.status == "RESOURCE_EXHAUSTED"
+    }
+
+    pub(crate) fn is_server_error(&self) -> bool {
+        self.error.code >= 500 || matches!(self.error.status.as_str(), "UNAVAILABLE" | "INTERNAL" | "DEADLINE_EXCEEDED")
+    }
+
+    /// The delay requested by a `google.rpc.RetryInfo` detail.
+    pub(crate) fn retry_delay(&self) -> Option<std::time::Duration> {
+        self.error
+            .details
+            .iter()
+            .filter_map(|d| d.retry_delay.as_deref())
+            .find_map(|d| d.strip_suffix('s').and_then(|s| s.parse::<f64>().ok()))
+            .filter(|s| s.is_finite() && *s >= 0.0)
+            .map(std::time::Duration::from_secs_f64)
+    }
+
+    /// Turn the body into the most specific [`ApiError`] we know of, falling back to [`ApiError::Api`].
+    pub(crate) fn into_api_error(self) -> ApiError {
+        if self.is_quota_exceeded() {
+            return ApiError::QuotaExceeded {
+                retry_after: self.retry_delay(),
+                error: Some(self),
+            };
+        }
+
+        self.typed_from_details().or_else(|| self.typed_from_message()).unwrap_or(ApiError::Api(self))
+    }
+
+    fn typed_from_details(&self) -> Option<ApiError> {
+        for detail in self.error.details.iter() {
+            if let Some(e) = detail.language_not_supported_by_attribute_error.as_ref() {
+                let languages = if e.detected_languages.is_empty() {
+                    e.requested_languages.clone()
+                } else {
+                    e.detected_languages.clone()
+                };
+                return Some(ApiError::LanguagesNotSupported {
+                    attribute: e.attribute.clone(),
+                    languages,
+                });
+            }
+
+            if detail.reason.as_deref() == Some("API_KEY_INVALID") {
+                return Some(ApiError::InvalidApiKey);
+            }
+
+            match detail.error_type.as_deref() {
+                Some("COMMENT_EMPTY") => return Some(ApiError::CommentEmpty),
+                Some("COMMENT_TOO_LONG") => return Some(ApiError::CommentTooLong),
+                Some("LANGUAGE_DETECTION_FAILED") | Some("UNKNOWN_LANGUAGE") => return Some(ApiError::UnknownLanguage),
+                _ => {}
+            }
+        }
+
+        None
+    }
+
+    /// Not every error carries details, so fall back to the messages the API is known to send.
+    fn typed_from_message(&self) -> Option<ApiError> {
+        let message = self.error.message.trim();
+
+        if message.starts_with("API key not valid") {
+            Some(ApiError::InvalidApiKey)
+        } else if message.starts_with("Comment must be non-empty") {
+            Some(ApiError::CommentEmpty)
+        } else if message.starts_with("Comment text too long") {
+            Some(ApiError::CommentTooLong)
+        } else if message.contains("Unable to detect language") {
+            Some(ApiError::UnknownLanguage)
+        } else if message.starts_with("Context can have either entries or article_and_parent_comment") {
+            Some(ApiError::InvalidContext)
+        } else if message.contains("only 'PLAIN_TEXT' comments are supported") {
+            Some(ApiError::UnsupportedCommentFormat)
+        } else if message.contains("requested_attributes") || message.contains("requested attribute") {
+            let attribute = message.rsplit_once(": ").map(|(_, a)| a.trim().to_string()).filter(|a| !a.is_empty());
+            Some(ApiError::MissingOrUnknownAttributes(attribute))
+        } else if let Some(rest) = message.strip_prefix("Attribute ") {
+            // "Attribute SPAM does not support request languages: es,fr"
+            let (attribute, languages) = rest.split_once(" does not support")?;
+            let (_, languages) = languages.split_once(": ")?;
+            Some(ApiError::LanguagesNotSupported {
+                attribute: attribute.to_string(),
+                languages: languages.split(',').map(|l| l.trim().to_string()).filter(|l| !l.is_empty()).collect(),
+            })
+        } else if let Some(rest) = message.strip_prefix("Requested score type ") {
+            // "Requested score type PROBABILITY is not supported by attribute TOXICITY"
+            let (score_type, attribute) = rest.split_once(" is not supported by attribute ")?;
+            Some(ApiError::UnsupportedScoreType {
+                score_type: score_type.to_string(),
+                attribute: attribute.trim_end_matches('.').to_string(),
+            })
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(body: &str) -> ApiError {
+        serde_json::from_str::<ApiErrorBody>(body).unwrap().into_api_error()
+    }
+
+    #[test]
+    fn test_invalid_api_key_from_error_info() {
+        let error = parse(
+            r#"{"error": {"code": 400, "message": "API key not valid. Please pass a valid API key.", "status": "INVALID_ARGUMENT",
+                "details": [{"@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "API_KEY_INVALID", "domain": "googleapis.com",
+                "metadata": {"service": "commentanalyzer.googleapis.com"}}]}}"#,
+        );
+        assert!(matches!(error, ApiError::InvalidApiKey));
+        assert!(error.is_client_error());
+        assert!(!error.is_retryable());
+    }
+
+    #[test]
+    fn test_languages_not_supported_from_details() {
+        let error = parse(
+            r#"{"error": {"code": 400, "message": "Attribute SPAM does not support request languages: es", "status": "INVALID_ARGUMENT",
+                "details": [{"@type": "type.googleapis.com/google.commentanalyzer.v1alpha1.Error", "errorType": "LANGUAGE_NOT_SUPPORTED_BY_ATTRIBUTE",
+                "languageNotSupportedByAttributeError": {"detectedLanguages": ["es"], "attribute": "SPAM"}}]}}"#,
+        );
+        match error {
+            ApiError::LanguagesNotSupported { attribute, languages } => {
+                assert_eq!(attribute, "SPAM");
+                assert_eq!(languages, vec!["es".to_string()]);
+            }
+            e => panic!("unexpected error: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn test_typed_errors_from_message() {
+        let error = parse(r#"{"error": {"code": 400, "message": "Comment must be non-empty.", "status": "INVALID_ARGUMENT"}}"#);
+        assert!(matches!(error, ApiError::CommentEmpty));
+
+        let error = parse(r#"{"error": {"code": 400, "message": "Requested score type RAW is not supported by attribute TOXICITY", "status": "INVALID_ARGUMENT"}}"#);
+        assert!(matches!(error, ApiError::UnsupportedScoreType { ref score_type, ref attribute } if score_type == "RAW" && attribute == "TOXICITY"));
+    }
+
+    #[test]
+    fn test_quota_and_unknown_errors() {
+        let error = parse(
+            r#"{"error": {"code": 429, "message": "Quota exceeded", "status": "RESOURCE_EXHAUSTED",
+                "details": [{"@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "30s"}]}}"#,
+        );
+        assert!(matches!(error, ApiError::QuotaExceeded { retry_after: Some(d), .. } if d == std::time::Duration::from_secs(30)));
+        assert!(error.is_retryable());
+
+        let error = parse(r#"{"error": {"code": 503, "message": "The service is currently unavailable.", "status": "UNAVAILABLE"}}"#);
+        assert!(matches!(error, ApiError::Api(_)));
+        assert!(error.is_retryable());
+        assert!(!error.is_client_error());
+    }
+}
@@ -1,9 +1,11 @@
 #![allow(dead_code)]
+mod error;
 mod request;
 mod response;
 
 use std::fmt::Display;
 
+pub use error::*;
 pub use request::*;
 pub use response::*;
 
@@ -20,26 +22,160 @@ impl RawApiResponse {
         match self {
             RawApiResponse::Valid(r) => Ok(r),
             RawApiResponse::ValidNoResponse(r) => Err(ApiError::EmptyResponse(r)),
-            RawApiResponse::Invalid(e) => Err(ApiError::Api(e)),
+            RawApiResponse::Invalid(e) => Err(e.into_api_error()),
         }
     }
+
+    /// Turn a raw HTTP response from the API into a [`Response`].
+    pub(crate) fn from_http(status: reqwest::StatusCode, headers: &reqwest::header::HeaderMap, body: String) -> Response {
+        let retry_after = headers
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(std::time::Duration::from_secs);
+
+        match serde_json::from_str::<RawApiResponse>(&body) {
+            // the header wins over a RetryInfo in the body
+            Ok(res) => match res.extract() {
+                Err(ApiError::QuotaExceeded { retry_after: from_body, error }) => Err(ApiError::QuotaExceeded {
+                    retry_after: retry_after.or(from_body),
+                    error,
+                }),
+                Err(ApiError::Api(error)) if status == reqwest::StatusCode::TOO_MANY_REQUESTS => Err(ApiError::QuotaExceeded { retry_after, error: Some(error) }),
+                res => res,
+            },
+            Err(_) if status == reqwest::StatusCode::TOO_MANY_REQUESTS => Err(ApiError::QuotaExceeded { retry_after, error: None }),
+            Err(_) if !status.is_success() => Err(ApiError::Status(status.as_u16(), body)),
+            Err(e) => Err(ApiError::Json(e, body)),
+        }
+    }
+}
+
+/// A request along with the priority it was queued with.
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct RequestWithPriority {
+    pub request: Request,
+    pub priority: Priority,
+}
+
+impl RequestWithPriority {
+    pub fn new(request: Request, priority: Priority) -> Self {
+        Self { request, priority }
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    pub fn into_inner(self) -> Request {
+        self.request
+    }
+}
+
+/// Identifies a request accepted by a client, so it can be cancelled while it is still waiting to be sent.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct RequestId(pub(crate) u64);
+
+impl Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{}", self.0)
+    }
+}
+
+/// How a client deals with requests that haven't been sent yet when it is shut down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Stop taking new requests but keep sending the queued ones at the usual rate, until everything has been
+    /// answered or `deadline` has passed. Whatever is still queued at the deadline is handed back, requests in flight
+    /// at that point are abandoned.
+    Drain { deadline: std::time::Duration },
+    /// Stop right away and hand back every request that hasn't been sent, requests in flight are abandoned.
+    Immediate,
+}
+
+/// The priority a request is queued with, higher numbers are more urgent.
+///
+/// Any level can be used, the constants are what `send_high`, `send_normal` and `send_low` use.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Priority(pub u8);
+
+impl Priority {
+    pub const HIGH: Priority = Priority(192);
+    pub const NORMAL: Priority = Priority(128);
+    pub const LOW: Priority = Priority(64);
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::NORMAL
+    }
+}
+
+impl Display for Priority {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// How a request is queued, a bare [`Priority`] converts into this for requests that don't need anything else.
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    pub priority: Priority,
+    /// If the request is still waiting to be sent at this point it is dropped and answered with [`ApiError::Expired`].
+    pub deadline: Option<std::time::Instant>,
+    /// Who the request is queued for when [`crate::Fairness`] is enabled, defaults to the request's `community_id`.
+    pub tenant: Option<String>,
+    /// Send the request even if [`crate::Cache`] has a response to it, the fresh response replaces the cached one.
+    pub bypass_cache: bool,
 }
 
-pub enum RequestWithPriority {
-    High(Request),
-    Normal(Request),
-    Low(Request),
+impl RequestOptions {
+    pub fn new(priority: Priority) -> Self {
+        Self {
+            priority,
+            ..Default::default()
+        }
+    }
+
+    pub fn tenant(mut self, tenant: impl Into<String>) -> Self {
+        self.tenant = Some(tenant.into());
+        self
+    }
+
+    pub fn deadline(mut self, deadline: std::time::Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Set the deadline to `ttl` from now.
+    pub fn ttl(self, ttl: std::time::Duration) -> Self {
+        self.deadline(std::time::Instant::now() + ttl)
+    }
+
+    pub fn bypass_cache(mut self) -> Self {
+        self.bypass_cache = true;
+        self
+    }
+
+    /// Who `req` is queued for, see [`RequestOptions::tenant`].
+    pub(crate) fn tenant_of(&self, req: &Request) -> Option<String> {
+        self.tenant.clone().or_else(|| req.community_id.clone())
+    }
+}
+
+impl From<Priority> for RequestOptions {
+    fn from(priority: Priority) -> Self {
+        Self::new(priority)
+    }
 }
 
 impl std::ops::Deref for RequestWithPriority {
     type Target = Request;
 
     fn deref(&self) -> &Self::Target {
-        match self {
-            RequestWithPriority::High(r) => r,
-            RequestWithPriority::Normal(r) => r,
-            RequestWithPriority::Low(r) => r,
-        }
+        &self.request
     }
 }
 
@@ -49,73 +185,117 @@ pub enum ApiError {
     ReceiverTaken,
     #[error("queue full")]
     QueueFull,
+    #[error("client has shut down before a response was received")]
+    ClientClosed,
+    /// The request was cancelled before it was sent, so it didn't use any quota.
+    #[error("request {0} was cancelled")]
+    Cancelled(RequestId),
+    /// The request's deadline passed before it was sent, so it didn't use any quota.
+    #[error("request {0} expired before it was sent")]
+    Expired(RequestId),
+    /// The url in the error has its query string and credentials removed.
     #[error("reqwest error: {0}")]
-    Reqwest(#[from] reqwest::Error),
+    Reqwest(reqwest::Error),
     #[error("json error: {0}\n{1:#?}")]
     Json(serde_json::Error, String),
+    /// The API answered with an unsuccessful HTTP status and a body that isn't a Google error.
+    #[error("http status {0}: {1:#?}")]
+    Status(u16, String),
     #[error("empty response: {0:#?}")]
     EmptyResponse(EmptyApiResponse),
+    /// The API answered with HTTP 429 or `RESOURCE_EXHAUSTED`, `retry_after` is taken from the `Retry-After` header.
+    #[error("quota exceeded")]
+    QuotaExceeded {
+        retry_after: Option<std::time::Duration>,
+        error: Option<ApiErrorBody>,
+    },
+    /// The cassette couldn't be loaded, or has no response recorded for the request.
+    #[error("cassette error: {0}")]
+    Cassette(String),
+    /// The client couldn't be started, because a thread couldn't be spawned.
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The last error of a request that was attempted more than once.
+    #[error("failed after {attempts} attempts: {source}")]
+    FailedAfterRetries { attempts: u32, source: Box<ApiError> },
+    /// The error of a request that identical requests were coalesced with, shared between all of them, the one that
+    /// was sent included. Match on [`ApiError::as_inner`] to see what went wrong.
+    #[error("{0}")]
+    Shared(std::sync::Arc<ApiError>),
 
-    // maybe fancy error parsing later, for now though
+    #[error("API key not valid. Please pass a valid API key.")]
+    InvalidApiKey,
+    #[error("Comment must be non-empty.")]
+    CommentEmpty,
+    #[error("Comment text too long.")]
+    CommentTooLong,
+    #[error("Missing requested_attributes or Unknown requested attributes: {0:?}")]
+    MissingOrUnknownAttributes(Option<String>),
+    #[error("Attribute {attribute} does not support languages: {languages:?}")]
+    LanguagesNotSupported { attribute: String, languages: Vec<String> },
+    #[error("Unable to detect language")]
+    UnknownLanguage,
+    #[error("Context can have either entries or article_and_parent_comment, but both fields were populated.")]
+    InvalidContext,
+    #[error("Currently, only 'PLAIN_TEXT' comments are supported")]
+    UnsupportedCommentFormat,
+    #[error("Requested score type {score_type} is not supported by attribute {attribute}")]
+    UnsupportedScoreType { score_type: String, attribute: String },
+    /// Any other error returned by the API.
     #[error("api error: {0}")]
     Api(ApiErrorBody),
-    // #[error("API key not valid. Please pass a valid API key.")]
-    // InvalidApiKey,
-    // #[error("Quota exceeded")]
-    // QuotaExceeded,
-    // #[error("Comment must be non-empty.")]
-    // CommentEmpty,
-    // #[error("Comment text too long.")]
-    // CommentTooLong,
-    // #[error("Missing requested_attributes or Unknown requested attributes: {0:?}")]
-    // MissingOrUnknownAttributes(Option<String>),
-    // #[error("Attribute {0} does not support languages: {1:?}")]
-    // LanguagesNotSupported(String, Vec<String>),
-    // #[error("Unable to detect language")]
-    // UnknownLanguage,
-    // #[error("Context can have either entries or article_and_parent_comment, but both fields were populated.")]
-    // InvalidContext,
-    // #[error("Currently, only 'PLAIN_TEXT' comments are supported")]
-    // UnsupportedCommentFormat,
-    // #[error("Requested score type {0} is not supported by attribute {1}")]
-    // UnsupportedScoreType(String, String),
 }
 
-// example error response
-// {
-//   "error": {
-//     "code": 400,
-//     "message": "API key not valid. Please pass a valid API key.",
-//     "status": "INVALID_ARGUMENT",
-//     "details": [
-//       {
-//         "@type": "type.googleapis.com/google.rpc.ErrorInfo",
-//         "reason": "API_KEY_INVALID",
-//         "domain": "googleapis.com",
-//         "metadata": {
-//           "service": "commentanalyzer.googleapis.com"
-//         }
-//       }
-//     ]
-//   }
-// }
-
-#[derive(serde::Deserialize, Debug)]
-pub struct ApiErrorBody {
-    error: ApiErrorBodyError,
+impl From<reqwest::Error> for ApiError {
+    fn from(mut e: reqwest::Error) -> Self {
+        if let Some(url) = e.url_mut() {
+            crate::api_key::scrub_url(url);
+        }
+        ApiError::Reqwest(e)
+    }
 }
 
-impl std::fmt::Display for ApiErrorBody {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.error.message)
+impl ApiError {
+    /// The error itself, looking through [`ApiError::Shared`], so coalesced requests can be matched on like any other.
+    pub fn as_inner(&self) -> &ApiError {
+        match self {
+            ApiError::Shared(e) => e.as_inner(),
+            e => e,
+        }
     }
-}
 
-#[derive(serde::Deserialize, Debug)]
-struct ApiErrorBodyError {
-    code: u16,
-    message: String,
-    status: String,
+    /// Whether the same request might succeed if it is sent again later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ApiError::Reqwest(e) => !e.is_builder(),
+            ApiError::QuotaExceeded { .. } => true,
+            ApiError::Status(code, _) => *code >= 500,
+            ApiError::Api(body) => body.is_server_error(),
+            ApiError::FailedAfterRetries { source, .. } => source.is_retryable(),
+            ApiError::Shared(e) => e.is_retryable(),
+            _ => false,
+        }
+    }
+
+    /// Whether the request itself (or the API key) was rejected, so sending it again will fail the same way.
+    pub fn is_client_error(&self) -> bool {
+        match self {
+            ApiError::InvalidApiKey
+            | ApiError::CommentEmpty
+            | ApiError::CommentTooLong
+            | ApiError::MissingOrUnknownAttributes(_)
+            | ApiError::LanguagesNotSupported { .. }
+            | ApiError::UnknownLanguage
+            | ApiError::InvalidContext
+            | ApiError::UnsupportedCommentFormat
+            | ApiError::UnsupportedScoreType { .. } => true,
+            ApiError::Status(code, _) => (400..500).contains(code) && *code != 429,
+            ApiError::Api(body) => (400..500).contains(&body.error.code) && !body.is_quota_exceeded(),
+            ApiError::FailedAfterRetries { source, .. } => source.is_client_error(),
+            ApiError::Shared(e) => e.is_client_error(),
+            _ => false,
+        }
+    }
 }
 
 /// The attribute types that the API can return.
@@ -1,10 +1,12 @@
-#[derive(serde::Serialize, derive_builder::Builder, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, derive_builder::Builder, Clone, Debug)]
 pub struct AttributeOptions {
     /// The score type returned for this attribute. Currently, only "PROBABILITY" is supported. Probability scores are in the range [0,1].
     #[builder(setter(into), default = "ScoreType::default()")]
+    #[serde(rename = "scoreType")]
     pub(crate) score_type: ScoreType,
     /// The API won't return scores that are below this threshold for this attribute. By default, all scores are returned.
     #[builder(default, setter(strip_option))]
+    #[serde(rename = "scoreThreshold", skip_serializing_if = "Option::is_none")]
     pub(crate) score_threshold: Option<f64>,
 }
 
@@ -17,14 +19,9 @@ impl Default for AttributeOptions {
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
 pub enum ScoreType {
+    #[default]
     #[serde(rename = "PROBABILITY")]
     Probability,
 }
-
-impl Default for ScoreType {
-    fn default() -> Self {
-        Self::Probability
-    }
-}
@@ -6,7 +6,7 @@ pub use attribute::*;
 use std::collections::HashMap;
 
 /// The request object for the `analyze` method.
-#[derive(serde::Serialize, derive_builder::Builder, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, derive_builder::Builder, Clone, Debug)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct Request {
     /// The comment data to analyze.
@@ -84,14 +84,14 @@ impl RequestBuilder {
 }
 
 /// The comment data to analyze.
-#[derive(serde::Serialize, derive_builder::Builder, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, derive_builder::Builder, Clone, Debug)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct Comment {
     /// The text to score. This is assumed to be utf8 raw text of the text to be checked. Emoji and other non-ascii characters can be included (HTML will probably result in lower performance).
     #[builder(setter(into))]
     pub(crate) text: String,
     /// The text type of comment.text. Either "PLAIN_TEXT" or "HTML". Currently only "PLAIN_TEXT" is supported.
-    #[serde(rename = "type")]
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     pub(crate) type_: Option<TextType>,
 }
@@ -108,7 +108,7 @@ where
 impl CommentBuilder {
     fn validate(&self) -> Result<(), String> {
         // ensure text does not exceed 20kb
-        if self.text.as_ref().map(|s| s.bytes().len()).unwrap_or(0) > 20_000 {
+        if self.text.as_ref().map(|s| s.len()).unwrap_or(0) > 20_000 {
             return Err("comment text cannot exceed 20kb".into());
         }
 
@@ -117,7 +117,7 @@ impl CommentBuilder {
 }
 
 /// The context of the comment.
-#[derive(serde::Serialize, derive_builder::Builder, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, derive_builder::Builder, Clone, Debug)]
 pub struct Context {
     /// A list of objects providing the context for comment. The API currently does not make use of this field, but it may influence API responses in the future.
     #[builder(setter(into))]
@@ -125,14 +125,14 @@ pub struct Context {
 }
 
 /// A context object.
-#[derive(serde::Serialize, derive_builder::Builder, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, derive_builder::Builder, Clone, Debug)]
 #[builder(build_fn(validate = "Self::validate"))]
 pub struct Entry {
     /// The text of a context object. The maximum size of context entry is 1MB.
     #[builder(setter(into))]
     pub(crate) text: String,
     /// The text type of the corresponding context text. Same type as comment.text. Currently only "PLAIN TEXT" is supported.
-    #[serde(rename = "type")]
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
     #[builder(setter(into, strip_option), default)]
     pub(crate) type_: Option<TextType>,
 }
@@ -140,7 +140,7 @@ pub struct Entry {
 impl EntryBuilder {
     fn validate(&self) -> Result<(), String> {
         // ensure text does not exceed 1MB
-        if self.text.as_ref().map(|s| s.bytes().len()).unwrap_or(0) > 1_000_000 {
+        if self.text.as_ref().map(|s| s.len()).unwrap_or(0) > 1_000_000 {
             return Err("context entry text cannot exceed 1MB".into());
         }
 
@@ -149,20 +149,15 @@ impl EntryBuilder {
 }
 
 /// The text type, either plain text or HTML. Currently only plain text is supported.
-#[derive(serde::Serialize, Clone, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, Default)]
 pub enum TextType {
+    #[default]
     #[serde(rename = "PLAIN_TEXT")]
     PlainText,
     #[serde(rename = "HTML")]
     Html,
 }
 
-impl Default for TextType {
-    fn default() -> Self {
-        Self::PlainText
-    }
-}
-
 /// The language codes that the API can accept.
 #[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum LanguageCode {
@@ -202,14 +197,59 @@ pub enum LanguageCode {
     Spanish,
     #[serde(rename = "sv")]
     Swedish,
-    #[serde(deserialize_with = "deserialize_unknown")]
+    /// Any other code, written as the plain code.
+    #[serde(untagged)]
     Other(String),
 }
 
-fn deserialize_unknown<'de, D>(deserializer: D) -> Result<String, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s = <String as serde::Deserialize>::deserialize(deserializer)?;
-    Ok(s)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_round_trips_in_wire_format() {
+        let wire = serde_json::json!({
+            "comment": { "text": "lol", "type": "PLAIN_TEXT" },
+            "context": { "entries": [{ "text": "what do you think?" }] },
+            "requestedAttributes": { "TOXICITY": { "scoreType": "PROBABILITY", "scoreThreshold": 0.5 } },
+            "spanAnnotations": true,
+            "languages": ["en", "hi-Latn", "xx"],
+            "doNotStore": true,
+            "clientToken": "token",
+            "sessionId": "session",
+            "communityId": "community"
+        });
+
+        let request = serde_json::from_value::<Request>(wire.clone()).unwrap();
+
+        assert_eq!(request.languages.as_ref().unwrap()[2], LanguageCode::Other("xx".into()));
+        assert_eq!(serde_json::to_value(&request).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_built_request_leaves_out_unset_fields() {
+        let request = RequestBuilder::default().comment("lol").add_attribute(Attribute::Toxicity, AttributeOptions::default()).build().unwrap();
+
+        let wire = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(
+            wire,
+            serde_json::json!({
+                "comment": { "text": "lol" },
+                "requestedAttributes": { "TOXICITY": { "scoreType": "PROBABILITY", "scoreThreshold": 0.0 } }
+            })
+        );
+        assert_eq!(serde_json::to_value(serde_json::from_value::<Request>(wire.clone()).unwrap()).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_request_with_priority_round_trips() {
+        let request = RequestBuilder::default().comment("lol").add_attribute(Attribute::Toxicity, AttributeOptions::default()).build().unwrap();
+        let request = crate::types::RequestWithPriority::new(request, crate::types::Priority::HIGH);
+
+        let wire = serde_json::to_value(&request).unwrap();
+
+        assert_eq!(wire["priority"], 192);
+        assert_eq!(serde_json::to_value(serde_json::from_value::<crate::types::RequestWithPriority>(wire.clone()).unwrap()).unwrap(), wire);
+    }
 }
@@ -51,31 +51,34 @@ pub type Response = Result<ApiResponse, super::ApiError>;
 // clientToken
 // Mirrors the request's clientToken.
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct ApiResponse {
     #[serde(rename = "attributeScores")]
     pub attribute_scores: std::collections::HashMap<super::Attribute, AttributeScores>,
     pub languages: Vec<super::LanguageCode>,
-    #[serde(rename = "clientToken")]
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none")]
     pub client_token: Option<String>,
+    /// How many attempts the client needed to get this response, 0 if it came from the [`crate::Cache`], not part of the API response.
+    #[serde(skip)]
+    pub attempts: u32,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct AttributeScores {
     #[serde(rename = "summaryScore")]
     pub summary_score: Score,
-    #[serde(rename = "spanScores")]
+    #[serde(rename = "spanScores", skip_serializing_if = "Option::is_none")]
     pub span_scores: Option<Vec<SpanScore>>,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct Score {
     pub value: f64,
     #[serde(rename = "type")]
     pub score_type: super::ScoreType,
 }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct SpanScore {
     pub begin: usize,
     pub end: usize,
@@ -92,9 +95,42 @@ pub struct SpanScore {
 //   ]
 // }
 
-#[derive(serde::Deserialize, Debug, Clone)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub struct EmptyApiResponse {
     pub languages: Vec<super::LanguageCode>,
-    #[serde(rename = "clientToken")]
+    #[serde(rename = "clientToken", skip_serializing_if = "Option::is_none")]
     pub client_token: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_response_round_trips_in_wire_format() {
+        let wire = serde_json::json!({
+            "attributeScores": {
+                "TOXICITY": {
+                    "summaryScore": { "value": 0.25, "type": "PROBABILITY" },
+                    "spanScores": [{ "begin": 0, "end": 3, "score": { "value": 0.25, "type": "PROBABILITY" } }]
+                },
+                "INSULT": { "summaryScore": { "value": 0.5, "type": "PROBABILITY" } }
+            },
+            "languages": ["en", "xx"],
+            "clientToken": "token"
+        });
+
+        let response = serde_json::from_value::<ApiResponse>(wire.clone()).unwrap();
+
+        assert_eq!(serde_json::to_value(&response).unwrap(), wire);
+    }
+
+    #[test]
+    fn test_empty_response_round_trips_in_wire_format() {
+        let wire = serde_json::json!({ "languages": ["de"] });
+
+        let response = serde_json::from_value::<EmptyApiResponse>(wire.clone()).unwrap();
+
+        assert_eq!(serde_json::to_value(&response).unwrap(), wire);
+    }
+}